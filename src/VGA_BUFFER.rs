@@ -1,6 +1,6 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use volatile::Volatile;
 
 #[allow(dead_code)]
@@ -159,8 +159,9 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    // use a spinning mutex for this to enable simple lock.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    // use a spinning mutex for this to enable simple lock. It disables
+    // interrupts while held, so printing from a handler cannot deadlock.
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new("WRITER", Writer {
         column_position: 0,
         colour_code: ColourCode::new(Colour::Green, Colour::Black),
         //unsafe reference to the buffer
@@ -191,12 +192,8 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    // Execute the write in a way that does not allow interrupts
-    // uring the write
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 // test single printline
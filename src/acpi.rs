@@ -0,0 +1,231 @@
+//! Discovery of the ACPI tables.
+//!
+//! The tables are found through the RSDP, which the BIOS leaves in either
+//! the first kilobyte of the extended BIOS data area or the read-only BIOS
+//! area below 1MiB. All tables are read through the physical memory mapping,
+//! so `memory::init` must have been called first. Addresses in the tables
+//! that are not valid physical addresses are treated like missing tables.
+
+use crate::memory;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The header shared by every system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The root system description pointer, including the ACPI 2.0 fields.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 part of the RSDP covered by the first checksum.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Read a `T` from physical memory. Returns `None` for an address that
+/// cannot be reached through the physical memory mapping.
+fn read_phys<T: Copy>(addr: u64) -> Option<T> {
+    let offset = memory::physical_memory_offset()?;
+    let addr = PhysAddr::try_new(addr).ok()?;
+    let ptr = VirtAddr::try_new(offset.as_u64().checked_add(addr.as_u64())?)
+        .ok()?
+        .as_ptr::<T>();
+
+    Some(unsafe { ptr.read_unaligned() })
+}
+
+/// Returns whether the `len` bytes at the physical address `addr` sum to
+/// zero, as every ACPI structure must.
+fn checksum_ok(addr: u64, len: usize) -> bool {
+    (0..len as u64)
+        .map(|i| read_phys::<u8>(addr + i).unwrap_or(1))
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte))
+        == 0
+}
+
+/// Search `start..end` on 16 byte boundaries for a valid RSDP.
+fn scan_for_rsdp(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        read_phys::<[u8; 8]>(addr).is_some_and(|sig| &sig == RSDP_SIGNATURE)
+            && checksum_ok(addr, RSDP_V1_LENGTH)
+    })
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    // The real mode segment of the EBDA is stored in the BIOS data area.
+    let ebda = u64::from(read_phys::<u16>(0x40e)?) << 4;
+
+    let addr = (if ebda != 0 {
+        scan_for_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    })
+    .or_else(|| scan_for_rsdp(0xe0000, 0x100000))?;
+
+    read_phys(addr)
+}
+
+/// Returns the physical address and header of the table at `addr`, if its
+/// checksum is valid.
+fn read_table(addr: u64) -> Option<(PhysAddr, SdtHeader)> {
+    // Table addresses come from the firmware and may be garbage.
+    let addr = PhysAddr::try_new(addr).ok()?;
+    let header: SdtHeader = read_phys(addr.as_u64())?;
+
+    if checksum_ok(addr.as_u64(), header.length as usize) {
+        Some((addr, header))
+    } else {
+        None
+    }
+}
+
+/// Find the table with the given signature, such as `b"APIC"` for the MADT.
+///
+/// Returns the physical address of its header.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+
+    // ACPI 2.0 and later use the XSDT with 64 bit entries.
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+    let (_, root_header) = read_table(root)?;
+
+    let entries_start = root + core::mem::size_of::<SdtHeader>() as u64;
+    let entries_end = root + u64::from(root_header.length);
+
+    (entries_start..entries_end)
+        .step_by(entry_size)
+        .filter_map(|entry| {
+            if entry_size == 8 {
+                read_phys::<u64>(entry)
+            } else {
+                read_phys::<u32>(entry).map(u64::from)
+            }
+        })
+        .filter_map(read_table)
+        .find(|(_, header)| &header.signature == signature)
+        .map(|(addr, _)| addr)
+}
+
+/// Maximum number of I/O APICs recorded from the MADT.
+pub const MAX_IO_APICS: usize = 4;
+
+/// Maximum number of interrupt source overrides recorded from the MADT.
+pub const MAX_OVERRIDES: usize = 16;
+
+/// An I/O APIC listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA interrupt that is not identity mapped to a global system
+/// interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags, holding the polarity and trigger mode.
+    pub flags: u16,
+}
+
+/// The interrupt controller information from the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the machine also has the legacy 8259 PICs.
+    pub has_legacy_pics: bool,
+    pub io_apics: [Option<IoApicEntry>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+const MADT_LOCAL_APIC_ADDRESS: usize = 36;
+const MADT_FLAGS: usize = 40;
+const MADT_ENTRIES: usize = 44;
+
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Find and parse the multiple APIC description table.
+pub fn madt() -> Option<Madt> {
+    let base = find_table(b"APIC")?.as_u64();
+    let header: SdtHeader = read_phys(base)?;
+    let end = base + u64::from(header.length);
+
+    let field = |offset: usize| base + offset as u64;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(read_phys::<u32>(field(
+            MADT_LOCAL_APIC_ADDRESS,
+        ))?)),
+        has_legacy_pics: read_phys::<u32>(field(MADT_FLAGS))? & 1 != 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+
+    let mut entry = field(MADT_ENTRIES);
+    while entry + 2 <= end {
+        let kind: u8 = read_phys(entry)?;
+        let len: u8 = read_phys(entry + 1)?;
+        if len < 2 {
+            break;
+        }
+
+        match kind {
+            ENTRY_IO_APIC => {
+                let io_apic = IoApicEntry {
+                    id: read_phys(entry + 2)?,
+                    address: PhysAddr::new(u64::from(read_phys::<u32>(entry + 4)?)),
+                    gsi_base: read_phys(entry + 8)?,
+                };
+                if let Some(slot) = madt.io_apics.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(io_apic);
+                }
+            }
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let irq_override = InterruptOverride {
+                    irq: read_phys(entry + 3)?,
+                    gsi: read_phys(entry + 4)?,
+                    flags: read_phys(entry + 8)?,
+                };
+                if let Some(slot) = madt.overrides.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(irq_override);
+                }
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = PhysAddr::try_new(read_phys(entry + 4)?).ok()?;
+            }
+            _ => {}
+        }
+        entry += u64::from(len);
+    }
+
+    Some(madt)
+}
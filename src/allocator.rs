@@ -1,6 +1,11 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::cmdline;
+use crate::memory::{self, protect, vmm};
 use crate::println;
+use crate::sync::{LockStats, Mutex, MutexGuard};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use fixed_size_block::FixedSizeBlockAllocator;
 
 use x86_64::{
@@ -16,40 +21,144 @@ pub mod linked_list;
 
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The size of the heap set up by `init_heap`, and the least that
+/// `boot_heap_size` picks.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
 
+/// The most that `boot_heap_size` picks from the memory map alone.
+pub const MAX_BOOT_HEAP_SIZE: usize = 32 * 1024 * 1024; // 32MiB
+
+/// Share of usable memory given to the heap at boot, in percent.
+const BOOT_HEAP_PERCENT: u64 = 5;
+
+/// The most the heap grows to, unless changed with `set_heap_limit`.
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024; // 64MiB
+
+/// The heap grows by at least this much at a time.
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_HEAP_LIMIT);
+
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
-/// A wrapper around a spin::Mutex to permit trait implementations
+/// A wrapper around a sync::Mutex to permit trait implementations.
+/// Interrupts stay enabled while it is held, so interrupt handlers must not
+/// allocate.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: Mutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: Mutex::new("ALLOCATOR", inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn stats(&self) -> LockStats {
+        self.inner.stats()
+    }
+}
+
+/// Contention statistics for the heap allocator's lock.
+pub fn lock_stats() -> LockStats {
+    ALLOCATOR.stats()
 }
 
-/// Initialise the heap memory stack
+/// Set the most the heap may grow to, in bytes. It is capped at the size of
+/// the heap's window of the address space, and never shrinks the heap.
+pub fn set_heap_limit(limit: usize) {
+    let limit = limit.min(vmm::HEAP_WINDOW_SIZE as usize);
+    HEAP_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// The most the heap may grow to, in bytes.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// The current size of the heap, in bytes.
+pub fn heap_size() -> usize {
+    ALLOCATOR.lock().size()
+}
+
+/// Map more memory at `top`, the end of the heap, to fit an allocation of
+/// `needed` bytes. Returns the number of bytes added, or 0 if the heap is at
+/// its limit, memory ran out or `memory::install` has not been called.
 ///
-/// This function creates the page range, then maps pages to the range.
+/// The heap never shrinks again: the fallback allocator can only be
+/// extended, so there is no way to take free pages back from its tail.
+///
+/// Called with the allocator locked, so it takes the kernel memory lock
+/// after it.
+fn grow_heap(top: usize, needed: usize) -> usize {
+    let limit = HEAP_START + heap_limit();
+    let size = align_up(needed.max(HEAP_GROW_STEP), 4096).min(limit.saturating_sub(top));
+    if size < needed {
+        return 0;
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    let mapped = memory::with_kernel_memory(|memory| {
+        vmm::map_range(memory, VirtAddr::new(top as u64), size as u64, flags)
+    });
+    match mapped {
+        Some(Ok(())) => size,
+        _ => 0,
+    }
+}
+
+/// Returns the size of the heap for a machine with `usable` bytes of usable
+/// memory: `BOOT_HEAP_PERCENT` of it, between `HEAP_SIZE` and
+/// `MAX_BOOT_HEAP_SIZE`, in whole pages.
+pub fn initial_heap_size(usable: u64) -> usize {
+    let share = (usable / 100 * BOOT_HEAP_PERCENT) as usize;
+    align_up(share.clamp(HEAP_SIZE, MAX_BOOT_HEAP_SIZE), 4096)
+}
+
+/// Returns the size of the heap to set up at boot. This is the `heap=`
+/// option of the kernel command line if it is given, or else sized from the
+/// usable memory in `memory_map`.
+pub fn boot_heap_size(memory_map: &MemoryMap) -> usize {
+    if let Some(size) = cmdline::size("heap") {
+        return align_up(size.clamp(4096, vmm::HEAP_WINDOW_SIZE as usize), 4096);
+    }
+
+    let usable = memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum();
+    initial_heap_size(usable)
+}
+
+/// Initialise the heap memory stack with `HEAP_SIZE` bytes.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    init_heap_sized(mapper, frame_allocator, HEAP_SIZE)
+}
+
+/// Initialise the heap memory stack with `size` bytes, a multiple of the
+/// page size. The heap limit is raised to `size` if it is lower.
+///
+/// This function creates the page range, then maps pages to the range.
+pub fn init_heap_sized(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    size: usize,
 ) -> Result<(), MapToError<Size4KiB>> {
     crate::print!("Initializing Heap...");
 
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
 
@@ -61,16 +170,17 @@ pub fn init_heap(
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
 
-        // allow read/write access to the heap
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        // allow read/write access to the heap, but never execution
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
 
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, size);
     }
-    println!("[ok]");
+    HEAP_LIMIT.fetch_max(size, Ordering::Relaxed);
+    println!("[ok] {} KiB", size / 1024);
     Ok(())
 }
 
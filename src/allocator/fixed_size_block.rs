@@ -39,8 +39,28 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start as *mut u8, size);
     }
 
-    /// Allocates using the fallback allocator when required.
+    /// The size of the heap, in bytes.
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// Allocates using the fallback allocator when required, growing the
+    /// heap once if it is full.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if self.fallback_allocator.size() == 0 {
+            return ptr::null_mut();
+        }
+
+        let top = self.fallback_allocator.top() as usize;
+        let added = super::grow_heap(top, layout.size() + layout.align());
+        if added == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(added) };
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
@@ -0,0 +1,67 @@
+//! The kernel command line.
+//!
+//! The bootloader does not pass a command line, so it is baked in when the
+//! kernel is built, from the `KERNEL_CMDLINE` environment variable:
+//!
+//! ```text
+//! KERNEL_CMDLINE="heap=16M" cargo run
+//! ```
+//!
+//! It is a list of `key=value` options separated by whitespace.
+
+/// Returns the whole command line.
+pub fn raw() -> &'static str {
+    option_env!("KERNEL_CMDLINE").unwrap_or("")
+}
+
+/// Returns the value of the last `key=value` option for `key`, if any.
+pub fn value(key: &str) -> Option<&'static str> {
+    find(raw(), key)
+}
+
+/// Returns the value of `key` parsed as a size, see `parse_size`.
+pub fn size(key: &str) -> Option<usize> {
+    value(key).and_then(parse_size)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .rev()
+        .filter_map(|option| option.split_once('='))
+        .find(|&(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+/// Parse a size in bytes, optionally followed by a `K`, `M` or `G` suffix.
+pub fn parse_size(value: &str) -> Option<usize> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[test_case]
+fn test_find_option() {
+    let cmdline = "quiet heap=4M  heap_limit=1G heap=8M";
+
+    assert_eq!(find(cmdline, "heap"), Some("8M"));
+    assert_eq!(find(cmdline, "heap_limit"), Some("1G"));
+    assert_eq!(find(cmdline, "quiet"), None);
+    assert_eq!(find(cmdline, "stack"), None);
+}
+
+#[test_case]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("16M"), Some(16 * 1024 * 1024));
+    assert_eq!(parse_size("2G"), Some(2 << 30));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("12x"), None);
+}
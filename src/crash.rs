@@ -0,0 +1,223 @@
+//! Crash reporting for fatal CPU exceptions.
+//!
+//! When an exception cannot be recovered from, the handler builds a report
+//! containing the exception, its decoded error information, the interrupted
+//! stack frame and the control registers. The report is written to both the
+//! serial port and the VGA buffer before handing over to the panic handler,
+//! so that tests fail with the full report on the host.
+
+use crate::{serial::SERIAL1, VGA_BUFFER::WRITER};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use uart_16550::SerialPort;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// An architecturally defined CPU exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    pub vector: u8,
+    pub mnemonic: &'static str,
+    pub name: &'static str,
+}
+
+impl Exception {
+    const fn new(vector: u8, mnemonic: &'static str, name: &'static str) -> Self {
+        Exception {
+            vector,
+            mnemonic,
+            name,
+        }
+    }
+}
+
+pub const DIVIDE_ERROR: Exception = Exception::new(0, "#DE", "DIVIDE ERROR");
+pub const DEBUG: Exception = Exception::new(1, "#DB", "DEBUG");
+pub const NON_MASKABLE_INTERRUPT: Exception = Exception::new(2, "NMI", "NON-MASKABLE INTERRUPT");
+pub const BREAKPOINT: Exception = Exception::new(3, "#BP", "BREAKPOINT");
+pub const OVERFLOW: Exception = Exception::new(4, "#OF", "OVERFLOW");
+pub const BOUND_RANGE_EXCEEDED: Exception = Exception::new(5, "#BR", "BOUND RANGE EXCEEDED");
+pub const INVALID_OPCODE: Exception = Exception::new(6, "#UD", "INVALID OPCODE");
+pub const DEVICE_NOT_AVAILABLE: Exception = Exception::new(7, "#NM", "DEVICE NOT AVAILABLE");
+pub const DOUBLE_FAULT: Exception = Exception::new(8, "#DF", "DOUBLE FAULT");
+pub const INVALID_TSS: Exception = Exception::new(10, "#TS", "INVALID TSS");
+pub const SEGMENT_NOT_PRESENT: Exception = Exception::new(11, "#NP", "SEGMENT NOT PRESENT");
+pub const STACK_SEGMENT_FAULT: Exception = Exception::new(12, "#SS", "STACK SEGMENT FAULT");
+pub const GENERAL_PROTECTION_FAULT: Exception =
+    Exception::new(13, "#GP", "GENERAL PROTECTION FAULT");
+pub const PAGE_FAULT: Exception = Exception::new(14, "#PF", "PAGE FAULT");
+pub const X87_FLOATING_POINT: Exception = Exception::new(16, "#MF", "X87 FLOATING POINT");
+pub const ALIGNMENT_CHECK: Exception = Exception::new(17, "#AC", "ALIGNMENT CHECK");
+pub const MACHINE_CHECK: Exception = Exception::new(18, "#MC", "MACHINE CHECK");
+pub const SIMD_FLOATING_POINT: Exception = Exception::new(19, "#XM", "SIMD FLOATING POINT");
+pub const VIRTUALIZATION: Exception = Exception::new(20, "#VE", "VIRTUALIZATION");
+pub const CONTROL_PROTECTION: Exception = Exception::new(21, "#CP", "CONTROL PROTECTION");
+pub const VMM_COMMUNICATION: Exception = Exception::new(29, "#VC", "VMM COMMUNICATION");
+pub const SECURITY: Exception = Exception::new(30, "#SX", "SECURITY");
+
+/// Every exception above, in vector order.
+const EXCEPTIONS: [Exception; 22] = [
+    DIVIDE_ERROR,
+    DEBUG,
+    NON_MASKABLE_INTERRUPT,
+    BREAKPOINT,
+    OVERFLOW,
+    BOUND_RANGE_EXCEEDED,
+    INVALID_OPCODE,
+    DEVICE_NOT_AVAILABLE,
+    DOUBLE_FAULT,
+    INVALID_TSS,
+    SEGMENT_NOT_PRESENT,
+    STACK_SEGMENT_FAULT,
+    GENERAL_PROTECTION_FAULT,
+    PAGE_FAULT,
+    X87_FLOATING_POINT,
+    ALIGNMENT_CHECK,
+    MACHINE_CHECK,
+    SIMD_FLOATING_POINT,
+    VIRTUALIZATION,
+    CONTROL_PROTECTION,
+    VMM_COMMUNICATION,
+    SECURITY,
+];
+
+/// Look up the exception raised on `vector`, if it is one.
+pub fn exception(vector: u8) -> Option<Exception> {
+    EXCEPTIONS.iter().copied().find(|e| e.vector == vector)
+}
+
+/// Writes the crash report to both the serial port and the VGA buffer.
+pub(crate) struct CrashWriter;
+
+/// Take over the output locks and return a writer to both outputs.
+///
+/// # Safety
+///
+/// The code holding the locks, if any, must never run again, as it would
+/// share the outputs with the returned writer.
+pub(crate) unsafe fn take_output() -> CrashWriter {
+    SERIAL1.force_unlock();
+    WRITER.force_unlock();
+    CrashWriter
+}
+
+impl Write for CrashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        SERIAL1.lock().write_str(s)?;
+        WRITER.lock().write_str(s)
+    }
+}
+
+/// Writes straight to COM1 without taking the lock around `SERIAL1`, for
+/// reports from code that interrupted a possible lock holder which then
+/// carries on, unlike with `take_output`.
+///
+/// Output may interleave with that of the lock holder.
+pub(crate) struct RawSerial(SerialPort);
+
+impl RawSerial {
+    /// Returns a writer for COM1. The port is set up when `SERIAL1` is
+    /// first used.
+    pub(crate) fn com1() -> Self {
+        RawSerial(unsafe { SerialPort::new(0x3F8) })
+    }
+}
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Print a crash report for the given exception and hand over to the panic
+/// handler.
+///
+/// `detail` carries the exception specific information, such as the decoded
+/// error code, and is printed between the header and the register dump.
+pub fn report(
+    exception: Exception,
+    detail: fmt::Arguments,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::interrupts::stats::record(exception.vector);
+
+    // The exception may have hit while the interrupted code was holding one
+    // of the output locks, and we will never return to release it.
+    let mut out = unsafe { take_output() };
+
+    // Nothing can be done if writing fails, so the results are ignored.
+    let _ = writeln!(out, "\n******** KERNEL CRASH ********");
+    let _ = writeln!(
+        out,
+        "EXCEPTION: {} ({}, vector {})",
+        exception.name, exception.mnemonic, exception.vector
+    );
+    let _ = writeln!(out, "{}", detail);
+    let _ = writeln!(out, "{:#?}", stack_frame);
+    let _ = write_control_registers(&mut out);
+    let _ = writeln!(out, "******************************");
+
+    panic!("{}", Header(exception));
+}
+
+/// The panic message of the crash report for an exception.
+struct Header(Exception);
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EXCEPTION: {} ({})", self.0.name, self.0.mnemonic)
+    }
+}
+
+/// Formatted text, truncated to fit without a heap.
+struct Buffer {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn format(args: fmt::Arguments) -> Buffer {
+        let mut buffer = Buffer {
+            bytes: [0; 128],
+            len: 0,
+        };
+        let _ = buffer.write_fmt(args);
+        buffer
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Returns whether `info` is the panic that `report` ends with for
+/// `exception`, for tests that expect a particular crash.
+pub fn is_report(info: &PanicInfo, exception: Exception) -> bool {
+    let message = Buffer::format(format_args!("{}", info.message()));
+    let expected = Buffer::format(format_args!("{}", Header(exception)));
+
+    message.as_bytes() == expected.as_bytes()
+}
+
+/// Dump the control registers relevant to a crash.
+fn write_control_registers(out: &mut impl Write) -> fmt::Result {
+    let (cr3_frame, cr3_flags) = Cr3::read();
+
+    writeln!(out, "CR0: {:?}", Cr0::read())?;
+    writeln!(out, "CR2: {:?}", Cr2::read())?;
+    writeln!(out, "CR3: {:?} {:?}", cr3_frame.start_address(), cr3_flags)?;
+    writeln!(out, "CR4: {:?}", Cr4::read())
+}
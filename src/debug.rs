@@ -0,0 +1,222 @@
+//! Kernel debugging support.
+//!
+//! The breakpoint (#BP) and debug (#DB) exceptions enter through the stubs
+//! in this module instead of the `x86-interrupt` ABI. The stubs save every
+//! general purpose register of the interrupted code in a [`TrapFrame`], which
+//! a debugger can inspect and modify before execution resumes.
+
+use crate::{memory, println};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::registers::debug::{Dr6, Dr6Flags};
+use x86_64::VirtAddr;
+
+pub mod backtrace;
+pub mod gdb;
+pub mod monitor;
+pub mod watchdog;
+pub mod watchpoint;
+
+/// The register state of the interrupted code.
+///
+/// The general purpose registers are pushed by the entry stubs, the rest is
+/// the frame pushed by the CPU. The field order must match the stubs.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Why the debugger was entered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapReason {
+    /// An `int3` instruction was executed.
+    Breakpoint,
+    /// A single step requested through the trap flag completed.
+    SingleStep,
+    /// Any other debug exception, with the conditions reported in DR6.
+    Debug(Dr6Flags),
+}
+
+/// Which debugger handles breakpoint and debug exceptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Backend {
+    /// Print the exception and continue, the default.
+    None,
+    /// The interactive monitor on COM1.
+    Monitor,
+    /// The GDB remote protocol stub on COM2.
+    Gdb,
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::None as u8);
+
+/// Select the debugger that breakpoint and debug exceptions drop into.
+pub fn set_backend(backend: Backend) {
+    BACKEND.store(backend as u8, Ordering::SeqCst);
+}
+
+/// Returns the currently selected debugger.
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::SeqCst) {
+        x if x == Backend::Monitor as u8 => Backend::Monitor,
+        x if x == Backend::Gdb as u8 => Backend::Gdb,
+        _ => Backend::None,
+    }
+}
+
+/// Scancode of the F12 key being pressed, which breaks into the selected
+/// debugger.
+pub const HOTKEY_SCANCODE: u8 = 0x58;
+
+/// The trap flag in RFLAGS, which raises a debug exception after the next
+/// instruction.
+pub const TRAP_FLAG: u64 = 1 << 8;
+
+/// The resume flag in RFLAGS, which suppresses instruction breakpoints for
+/// the next instruction so that execution can continue past one.
+const RESUME_FLAG: u64 = 1 << 16;
+
+// Entry stubs for #DB (vector 1) and #BP (vector 3). Neither pushes an error
+// code, so the CPU leaves the stack 8 bytes off a 16 byte boundary. Pushing
+// the 15 registers realigns it for the call.
+macro_rules! trap_entry {
+    ($name:literal, $vector:literal) => {
+        global_asm!(
+            concat!(".global ", $name),
+            concat!($name, ":"),
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rdi, rsp",
+            concat!("mov esi, ", $vector),
+            "cld",
+            "call {handler}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "iretq",
+            handler = sym debug_trap,
+        );
+    };
+}
+
+trap_entry!("debug_exception_entry", 1);
+trap_entry!("breakpoint_entry", 3);
+
+extern "C" {
+    fn debug_exception_entry();
+    fn breakpoint_entry();
+}
+
+/// Address of the #DB entry stub, for the IDT.
+pub fn debug_exception_entry_addr() -> VirtAddr {
+    VirtAddr::from_ptr(debug_exception_entry as *const ())
+}
+
+/// Address of the #BP entry stub, for the IDT.
+pub fn breakpoint_entry_addr() -> VirtAddr {
+    VirtAddr::from_ptr(breakpoint_entry as *const ())
+}
+
+/// Common handler for the entry stubs.
+extern "C" fn debug_trap(frame: &mut TrapFrame, vector: u64) {
+    crate::interrupts::stats::record(vector as u8);
+
+    let reason = if vector == 3 {
+        TrapReason::Breakpoint
+    } else {
+        let dr6 = Dr6::read();
+        clear_dr6();
+
+        if dr6.contains(Dr6Flags::STEP) {
+            TrapReason::SingleStep
+        } else {
+            TrapReason::Debug(dr6)
+        }
+    };
+
+    let watchpoint_hit = match reason {
+        TrapReason::Debug(dr6) if dr6.intersects(Dr6Flags::TRAP) => {
+            watchpoint::report(frame, dr6);
+            frame.rflags |= RESUME_FLAG;
+            true
+        }
+        _ => false,
+    };
+
+    match backend() {
+        Backend::Monitor => monitor::enter(frame, reason),
+        Backend::Gdb => gdb::enter(frame, reason),
+        Backend::None => match reason {
+            TrapReason::Breakpoint => println!("EXEPTION: BREAKPOINT\n{:#x?}", frame),
+            _ if watchpoint_hit => {}
+            _ => println!("EXCEPTION: DEBUG\n{:?}\n{:#x?}", reason, frame),
+        },
+    }
+}
+
+/// DR6 is never cleared by the CPU, so stale conditions are cleared once
+/// they have been read.
+fn clear_dr6() {
+    unsafe {
+        core::arch::asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack));
+    }
+}
+
+/// Returns whether every byte of `addr..addr + len` is mapped.
+pub(crate) fn range_is_mapped(addr: u64, len: u64) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let first_page = addr & !0xfff;
+
+    (first_page..end).step_by(4096).all(|page| {
+        VirtAddr::try_new(page).is_ok_and(|page| memory::walk(page).is_some())
+    })
+}
@@ -0,0 +1,96 @@
+//! Stack backtraces by following the chain of saved frame pointers.
+//!
+//! This only works because the kernel is built with frame pointers forced
+//! on, see `.cargo/config.toml`. Frames are only followed while they are
+//! mapped, so a corrupted chain ends the backtrace instead of faulting.
+
+use super::range_is_mapped;
+use core::fmt;
+
+/// Stop walking after this many frames, in case the chain loops.
+const MAX_DEPTH: usize = 32;
+
+/// A backtrace starting at a saved frame pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    rbp: u64,
+}
+
+impl Backtrace {
+    /// A backtrace of the code whose frame pointer is `rbp`, such as the
+    /// `rbp` of a trap frame.
+    pub fn from_frame_pointer(rbp: u64) -> Self {
+        Backtrace { rbp }
+    }
+
+    /// A backtrace of the caller.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+        }
+        Backtrace { rbp }
+    }
+
+    /// A backtrace of the code an `extern "x86-interrupt"` handler
+    /// interrupted, starting at the caller of the interrupted function.
+    ///
+    /// The handler's prologue saves the interrupted frame pointer at the
+    /// bottom of its own frame, so this must be called from the handler
+    /// itself.
+    #[inline(always)]
+    pub fn interrupted() -> Self {
+        let rbp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, [rbp]", out(reg) rbp, options(readonly, nostack));
+        }
+        Backtrace { rbp }
+    }
+
+    /// Call `visit` with the return address of each frame, innermost first.
+    pub fn walk(&self, mut visit: impl FnMut(u64)) {
+        let mut rbp = self.rbp;
+
+        for _ in 0..MAX_DEPTH {
+            if rbp == 0 || !rbp.is_multiple_of(8) || !range_is_mapped(rbp, 16) {
+                break;
+            }
+
+            // A frame starts with the caller's frame pointer followed by the
+            // return address.
+            let frame = rbp as *const u64;
+            let (next, return_addr) = unsafe { (frame.read(), frame.add(1).read()) };
+            if return_addr == 0 {
+                break;
+            }
+            visit(return_addr);
+
+            // The stack grows down, so callers always live at higher addresses.
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = Ok(());
+        let mut depth = 0;
+
+        self.walk(|return_addr| {
+            if result.is_ok() {
+                result = writeln!(f, "  #{:<2} {:#018x}", depth, return_addr);
+            }
+            depth += 1;
+        });
+        result?;
+
+        if depth == 0 {
+            writeln!(f, "  <no frames>")?;
+        }
+        Ok(())
+    }
+}
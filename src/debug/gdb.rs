@@ -0,0 +1,602 @@
+//! GDB remote serial protocol stub on COM2.
+//!
+//! Once selected with `debug::set_backend(Backend::Gdb)`, the next breakpoint
+//! or press of the debugger hotkey stops the kernel and waits for GDB. Under
+//! QEMU the second serial port can be exposed with
+//! `-serial stdio -serial tcp::1234,server,nowait`, after which GDB connects
+//! with `target remote :1234`.
+//!
+//! Registers, memory, software breakpoints, hardware breakpoints and write or
+//! access watchpoints, single-step and continue are supported. Software
+//! breakpoints are only written into memory while the kernel runs, so GDB
+//! always reads the original instructions.
+
+use super::watchpoint::{self, WatchKind, Watchpoint, WATCHPOINT_COUNT};
+use super::{range_is_mapped, Backend, TrapFrame, TrapReason, TRAP_FLAG};
+use crate::serial::SERIAL2;
+use core::fmt::{self, Write};
+use crate::sync::MutexGuard;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::VirtAddr;
+
+/// Largest packet we accept or send, advertised to GDB as `PacketSize`.
+const MAX_PACKET: usize = 0x400;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+
+/// Stop reply for SIGTRAP, the only signal we report.
+const STOP_REPLY: &str = "S05";
+/// Error reply for memory that cannot be accessed (EFAULT).
+const MEMORY_ERROR: &str = "E0e";
+/// Error reply for a packet whose data is not valid hex.
+const DATA_ERROR: &str = "E01";
+
+/// A software breakpoint set by GDB.
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte replaced by `int3` while the breakpoint is inserted.
+    original: u8,
+    inserted: bool,
+}
+
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Whether GDB has talked to us, in which case it expects a stop reply.
+    connected: bool,
+    /// Set while single-stepping off a breakpoint on the way to continuing.
+    stepping_over: bool,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        const NONE: Option<Breakpoint> = None;
+        Stub {
+            breakpoints: [NONE; MAX_BREAKPOINTS],
+            connected: false,
+            stepping_over: false,
+        }
+    }
+
+    fn is_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.iter().flatten().any(|bp| bp.addr == addr)
+    }
+
+    fn add(&mut self, addr: u64) -> bool {
+        if self.is_breakpoint(addr) {
+            return true;
+        }
+        match self.breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => {
+                *slot = Some(Breakpoint {
+                    addr,
+                    original: 0,
+                    inserted: false,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, addr: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if matches!(slot, Some(bp) if bp.addr == addr) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Write `int3` at every breakpoint except `skip`.
+    fn insert_all(&mut self, skip: Option<u64>) {
+        for bp in self.breakpoints.iter_mut().flatten() {
+            if Some(bp.addr) != skip && !bp.inserted {
+                bp.original = unsafe { (bp.addr as *const u8).read_volatile() };
+                unsafe { poke(bp.addr, INT3) };
+                bp.inserted = true;
+            }
+        }
+    }
+
+    /// Restore the original bytes at every inserted breakpoint.
+    fn remove_all(&mut self) {
+        for bp in self.breakpoints.iter_mut().flatten() {
+            if bp.inserted {
+                unsafe { poke(bp.addr, bp.original) };
+                bp.inserted = false;
+            }
+        }
+    }
+}
+
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+/// Write a byte of memory, even if it is mapped read-only.
+///
+/// This is unsafe because the caller must guarantee that `addr` is mapped and
+/// that overwriting it is what the debugger asked for.
+pub(super) unsafe fn poke(addr: u64, byte: u8) {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    (addr as *mut u8).write_volatile(byte);
+    Cr0::write(cr0);
+}
+
+/// A reply packet being built.
+struct Response {
+    buffer: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Response {
+    fn new() -> Self {
+        Response {
+            buffer: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Append `value` as `size` little endian bytes in hex, as GDB expects
+    /// registers.
+    fn push_le(&mut self, value: u64, size: usize) {
+        for byte in value.to_le_bytes().iter().take(size) {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+}
+
+impl Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// The serial line to GDB.
+struct Connection<'a> {
+    port: MutexGuard<'a, SerialPort>,
+}
+
+impl Connection<'_> {
+    fn lock() -> Self {
+        Connection {
+            port: SERIAL2.lock(),
+        }
+    }
+
+    /// Wait for a packet with a valid checksum and acknowledge it.
+    fn receive_packet<'b>(&mut self, buffer: &'b mut [u8; MAX_PACKET]) -> &'b [u8] {
+        loop {
+            while self.port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                match self.port.receive() {
+                    b'#' => break,
+                    // A new packet started before this one ended.
+                    b'$' => {
+                        len = 0;
+                        checksum = 0;
+                    }
+                    byte => {
+                        if len < buffer.len() {
+                            buffer[len] = byte;
+                            len += 1;
+                        }
+                        checksum = checksum.wrapping_add(byte);
+                    }
+                }
+            }
+
+            let high = hex_value(self.port.receive());
+            let low = hex_value(self.port.receive());
+            if let (Some(high), Some(low)) = (high, low) {
+                if high << 4 | low == checksum {
+                    self.port.send(b'+');
+                    return &buffer[..len];
+                }
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Send a packet, retransmitting it until GDB acknowledges it.
+    fn send_packet(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send(b'$');
+            for &byte in data {
+                self.port.send(byte);
+            }
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[usize::from(checksum >> 4)]);
+            self.port.send(HEX_DIGITS[usize::from(checksum & 0xf)]);
+
+            if self.port.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+/// Parse a big endian hex number, as used for addresses and lengths.
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |value, &digit| {
+        Some(value << 4 | u64::from(hex_value(digit)?))
+    })
+}
+
+/// Parse hex encoded bytes as a little endian number, as used for registers.
+fn parse_le(hex: &[u8]) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    hex.chunks(2)
+        .enumerate()
+        .try_fold(0u64, |value, (i, pair)| {
+            let byte = parse_hex(pair)?;
+            Some(value | byte << (8 * i))
+        })
+}
+
+/// Split `addr,len` (optionally followed by more fields) into numbers.
+fn parse_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let mut fields = args.split(|&byte| byte == b',' || byte == b':');
+    let addr = parse_hex(fields.next()?)?;
+    let len = parse_hex(fields.next()?)?;
+    Some((addr, len))
+}
+
+/// Number of registers in the `g` packet: 16 general purpose registers, rip,
+/// eflags and the six segment registers.
+const REGISTER_COUNT: usize = 24;
+
+/// Returns the size of register `n` in the `g` packet, in bytes.
+fn register_size(n: usize) -> usize {
+    if n <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+/// Returns register `n` in GDB's amd64 numbering.
+fn read_register(frame: &TrapFrame, n: usize) -> Option<u64> {
+    let value = match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20 => u64::from(DS::get_reg().0),
+        21 => u64::from(ES::get_reg().0),
+        22 => u64::from(FS::get_reg().0),
+        23 => u64::from(GS::get_reg().0),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Returns a writable reference to register `n`. Segment registers cannot be
+/// changed from GDB.
+fn register_mut(frame: &mut TrapFrame, n: usize) -> Option<&mut u64> {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return None,
+    };
+    Some(register)
+}
+
+/// Maps the type of a `Z` packet to a hardware watchpoint.
+fn watch_kind(kind: u8) -> Option<WatchKind> {
+    match kind {
+        b'1' => Some(WatchKind::Execute),
+        b'2' => Some(WatchKind::Write),
+        b'4' => Some(WatchKind::Access),
+        _ => None,
+    }
+}
+
+/// What to do once a packet has been handled.
+enum Action {
+    Reply,
+    Continue,
+    Step,
+    Detach,
+}
+
+/// Talk to GDB until it resumes execution.
+pub fn enter(frame: &mut TrapFrame, reason: TrapReason) {
+    let _watchdog = super::watchdog::suspend();
+
+    // Interrupts are disabled in the trap, so nothing else can hold the lock.
+    let mut stub = STUB.lock();
+    stub.remove_all();
+
+    frame.rflags &= !TRAP_FLAG;
+
+    // Report the breakpoint address rather than the address after `int3`.
+    if reason == TrapReason::Breakpoint && stub.is_breakpoint(frame.rip - 1) {
+        frame.rip -= 1;
+    }
+
+    // Stepping off a breakpoint to continue; put it back and keep going.
+    if stub.stepping_over {
+        stub.stepping_over = false;
+        if reason == TrapReason::SingleStep {
+            stub.insert_all(None);
+            return;
+        }
+    }
+
+    let mut connection = Connection::lock();
+    if stub.connected {
+        connection.send_packet(STOP_REPLY.as_bytes());
+    }
+
+    let mut buffer = [0u8; MAX_PACKET];
+    loop {
+        let packet = connection.receive_packet(&mut buffer);
+        stub.connected = true;
+
+        let mut response = Response::new();
+        let action = handle_packet(&mut stub, frame, packet, &mut response);
+
+        match action {
+            Action::Reply => connection.send_packet(response.as_bytes()),
+            Action::Detach => {
+                connection.send_packet(response.as_bytes());
+                return;
+            }
+            Action::Step => {
+                frame.rflags |= TRAP_FLAG;
+                stub.insert_all(Some(frame.rip));
+                return;
+            }
+            Action::Continue => {
+                if stub.is_breakpoint(frame.rip) {
+                    // Execute the original instruction before inserting the
+                    // breakpoint at it again.
+                    frame.rflags |= TRAP_FLAG;
+                    stub.stepping_over = true;
+                    stub.insert_all(Some(frame.rip));
+                } else {
+                    stub.insert_all(None);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Handle a single packet, writing any reply into `response`.
+fn handle_packet(
+    stub: &mut Stub,
+    frame: &mut TrapFrame,
+    packet: &[u8],
+    response: &mut Response,
+) -> Action {
+    let (&command, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Reply,
+    };
+
+    // A failed write only truncates the reply, which GDB reports itself.
+    let _ = match command {
+        b'?' => response.write_str(STOP_REPLY),
+        b'g' => {
+            for n in 0..REGISTER_COUNT {
+                let value = read_register(frame, n).unwrap_or(0);
+                response.push_le(value, register_size(n));
+            }
+            Ok(())
+        }
+        b'G' => {
+            let mut offset = 0;
+            for n in 0..REGISTER_COUNT {
+                let size = register_size(n) * 2;
+                let value = args.get(offset..offset + size).and_then(parse_le);
+                if let (Some(value), Some(register)) = (value, register_mut(frame, n)) {
+                    *register = value;
+                }
+                offset += size;
+            }
+            response.write_str("OK")
+        }
+        b'p' => {
+            let n = parse_hex(args).unwrap_or(u64::MAX) as usize;
+            match read_register(frame, n) {
+                Some(value) => {
+                    response.push_le(value, register_size(n));
+                    Ok(())
+                }
+                None => response.write_str("E00"),
+            }
+        }
+        b'P' => {
+            let mut fields = args.splitn(2, |&byte| byte == b'=');
+            let n = fields.next().and_then(parse_hex);
+            let value = fields.next().and_then(parse_le);
+            match (n, value) {
+                (Some(n), Some(value)) => match register_mut(frame, n as usize) {
+                    Some(register) => {
+                        *register = value;
+                        response.write_str("OK")
+                    }
+                    None => response.write_str("E00"),
+                },
+                _ => response.write_str("E00"),
+            }
+        }
+        b'm' => match parse_addr_len(args) {
+            Some((addr, len)) => {
+                let len = len.min(MAX_PACKET as u64 / 2);
+                if range_is_mapped(addr, len) {
+                    for i in 0..len {
+                        let byte = unsafe { ((addr + i) as *const u8).read_volatile() };
+                        let _ = write!(response, "{:02x}", byte);
+                    }
+                    Ok(())
+                } else {
+                    response.write_str(MEMORY_ERROR)
+                }
+            }
+            None => response.write_str("E00"),
+        },
+        b'M' => {
+            let data = args.splitn(2, |&byte| byte == b':').nth(1).unwrap_or(&[]);
+            match parse_addr_len(args) {
+                Some((addr, len)) if data.len() as u64 == len * 2 => {
+                    // Nothing is written unless all of the data is valid.
+                    if !data.iter().all(|&digit| hex_value(digit).is_some()) {
+                        response.write_str(DATA_ERROR)
+                    } else if range_is_mapped(addr, len) {
+                        for (i, pair) in data.chunks(2).enumerate() {
+                            if let Some(byte) = parse_hex(pair) {
+                                unsafe { poke(addr + i as u64, byte as u8) };
+                            }
+                        }
+                        response.write_str("OK")
+                    } else {
+                        response.write_str(MEMORY_ERROR)
+                    }
+                }
+                _ => response.write_str("E00"),
+            }
+        }
+        b'Z' | b'z' => match (args.first(), args.get(2..).and_then(parse_addr_len)) {
+            (Some(b'0'), Some((addr, _))) => {
+                if command == b'z' {
+                    stub.remove(addr);
+                    response.write_str("OK")
+                } else if !range_is_mapped(addr, 1) {
+                    response.write_str(MEMORY_ERROR)
+                } else if stub.add(addr) {
+                    response.write_str("OK")
+                } else {
+                    response.write_str("E00")
+                }
+            }
+            (Some(&kind), Some((addr, len))) => match watch_kind(kind) {
+                Some(kind) if command == b'Z' => {
+                    let result = VirtAddr::try_new(addr).ok().and_then(|addr| {
+                        let len = if kind == WatchKind::Execute {
+                            1
+                        } else {
+                            len as usize
+                        };
+                        watchpoint::set_any(Watchpoint { addr, kind, len }).ok()
+                    });
+                    match result {
+                        Some(_) => response.write_str("OK"),
+                        None => response.write_str("E00"),
+                    }
+                }
+                Some(kind) => {
+                    for slot in 0..WATCHPOINT_COUNT {
+                        if matches!(watchpoint::get(slot),
+                            Some(wp) if wp.addr.as_u64() == addr && wp.kind == kind)
+                        {
+                            let _ = watchpoint::clear(slot);
+                        }
+                    }
+                    response.write_str("OK")
+                }
+                // Read-only watchpoints cannot be done by the hardware.
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        },
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            return if command == b'c' {
+                Action::Continue
+            } else {
+                Action::Step
+            };
+        }
+        b'D' => {
+            // Detach: forget everything and let the kernel run freely.
+            *stub = Stub::new();
+            super::set_backend(Backend::None);
+            let _ = response.write_str("OK");
+            return Action::Detach;
+        }
+        b'k' => {
+            crate::exit_qemu(crate::QemuExitCode::Success);
+            crate::hlt_loop();
+        }
+        b'H' => response.write_str("OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                write!(response, "PacketSize={:x}", MAX_PACKET)
+            } else if args == b"Attached" {
+                response.write_str("1")
+            } else if args == b"C" {
+                response.write_str("QC1")
+            } else {
+                Ok(())
+            }
+        }
+        // An empty reply tells GDB the command is not supported.
+        _ => Ok(()),
+    };
+    Action::Reply
+}
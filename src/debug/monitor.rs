@@ -0,0 +1,384 @@
+//! Interactive kernel monitor on COM1.
+//!
+//! The monitor is entered from breakpoints, completed single steps and the
+//! F12 hotkey once it has been selected with
+//! `debug::set_backend(Backend::Monitor)`. The whole kernel is stopped with
+//! interrupts disabled while the monitor is waiting for input.
+
+use super::backtrace::Backtrace;
+use super::watchpoint::{self, WatchKind, Watchpoint, WATCHPOINT_COUNT};
+use super::{range_is_mapped, TrapFrame, TrapReason, TRAP_FLAG};
+use crate::{interrupts, memory, rtc};
+use crate::serial::SERIAL1;
+use crate::sync::MutexGuard;
+use core::fmt::{self, Write};
+use uart_16550::SerialPort;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::VirtAddr;
+
+const PROMPT: &str = "kmon> ";
+const LINE_LENGTH: usize = 128;
+const HELP: &str = "\
+commands:
+  regs                    show the registers
+  set <reg> <value>       set a general purpose register, rip or rflags
+  x <addr> [len]          dump memory as hex (len defaults to 64)
+  w <addr> <byte>...      write bytes to memory
+  pt <addr>               walk the page tables for an address
+  bt                      show a backtrace
+  irqs                    show interrupt counts per vector
+  date                    show the wall clock time
+  locks                   show lock contention statistics
+  maps                    list the mapped regions of the address space
+  wp                      list the hardware watchpoints
+  wp set <addr> <x|w|rw> [len]
+                          watch execution, writes or accesses (len 1, 2, 4, 8)
+  wp clear <slot>         remove a hardware watchpoint
+  s, step                 execute a single instruction
+  c, continue             resume execution
+numbers are hexadecimal, with or without a 0x prefix";
+
+/// The serial console the monitor talks over.
+struct Console<'a> {
+    port: MutexGuard<'a, SerialPort>,
+}
+
+impl Console<'_> {
+    fn lock() -> Self {
+        // The trap may have hit while the interrupted code was printing, and
+        // it cannot release the lock until the monitor returns.
+        if SERIAL1.try_lock().is_none() {
+            unsafe { SERIAL1.force_unlock() };
+        }
+        Console {
+            port: SERIAL1.lock(),
+        }
+    }
+
+    /// Read a line, echoing it back and handling backspace.
+    fn read_line<'b>(&mut self, buffer: &'b mut [u8; LINE_LENGTH]) -> &'b str {
+        let mut len = 0;
+        loop {
+            match self.port.receive() {
+                b'\r' | b'\n' => {
+                    self.port.send(b'\r');
+                    self.port.send(b'\n');
+                    break;
+                }
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        for &byte in b"\x08 \x08" {
+                            self.port.send(byte);
+                        }
+                    }
+                }
+                byte @ 0x20..=0x7e if len < buffer.len() => {
+                    buffer[len] = byte;
+                    len += 1;
+                    self.port.send(byte);
+                }
+                _ => {}
+            }
+        }
+        // Only printable ASCII is stored, so this cannot fail.
+        core::str::from_utf8(&buffer[..len]).unwrap_or("")
+    }
+}
+
+impl Write for Console<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.port.write_str(s)
+    }
+}
+
+/// Run the monitor until the user continues or steps.
+///
+/// Any changes made to `frame` are applied when the trap returns.
+pub fn enter(frame: &mut TrapFrame, reason: TrapReason) {
+    let _watchdog = super::watchdog::suspend();
+    let mut console = Console::lock();
+    let mut buffer = [0u8; LINE_LENGTH];
+
+    // Single stepping stops unless it is requested again.
+    frame.rflags &= !TRAP_FLAG;
+
+    let _ = writeln!(console, "\n{:?} at {:#x}", reason, frame.rip);
+
+    loop {
+        let _ = console.write_str(PROMPT);
+        let line = console.read_line(&mut buffer);
+        let mut args = line.split_whitespace();
+
+        let result = match args.next() {
+            None => Ok(()),
+            Some("help") | Some("?") => writeln!(console, "{}", HELP),
+            Some("regs") | Some("r") => print_registers(&mut console, frame),
+            Some("set") => set_register(&mut console, frame, args.next(), args.next()),
+            Some("x") => dump_memory(&mut console, args.next(), args.next()),
+            Some("w") => write_memory(&mut console, args.next(), args),
+            Some("pt") => match args.next().and_then(parse_number) {
+                Some(addr) => page_walk(&mut console, addr),
+                None => writeln!(console, "usage: pt <addr>"),
+            },
+            Some("bt") => writeln!(
+                console,
+                "  rip {:#018x}\n{}",
+                frame.rip,
+                Backtrace::from_frame_pointer(frame.rbp)
+            ),
+            Some("wp") => watchpoint_command(&mut console, args),
+            Some("irqs") => writeln!(console, "{}", interrupts::stats::report()),
+            Some("date") => writeln!(console, "{}", rtc::SystemTime::now()),
+            Some("locks") => writeln!(console, "{}", crate::sync::report()),
+            Some("maps") => write!(console, "{}", memory::dump::address_space()),
+            Some("s") | Some("step") => {
+                frame.rflags |= TRAP_FLAG;
+                return;
+            }
+            Some("c") | Some("continue") => return,
+            Some(command) => writeln!(console, "unknown command '{}', try 'help'", command),
+        };
+        // Nothing else can be reported to if the serial port fails.
+        let _ = result;
+    }
+}
+
+/// Parse a hexadecimal number, with or without a `0x` prefix.
+fn parse_number(s: &str) -> Option<u64> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+fn print_registers(out: &mut impl Write, frame: &TrapFrame) -> fmt::Result {
+    writeln!(
+        out,
+        "rax {:016x}  rbx {:016x}  rcx {:016x}",
+        frame.rax, frame.rbx, frame.rcx
+    )?;
+    writeln!(
+        out,
+        "rdx {:016x}  rsi {:016x}  rdi {:016x}",
+        frame.rdx, frame.rsi, frame.rdi
+    )?;
+    writeln!(
+        out,
+        "rbp {:016x}  rsp {:016x}  r8  {:016x}",
+        frame.rbp, frame.rsp, frame.r8
+    )?;
+    writeln!(
+        out,
+        "r9  {:016x}  r10 {:016x}  r11 {:016x}",
+        frame.r9, frame.r10, frame.r11
+    )?;
+    writeln!(
+        out,
+        "r12 {:016x}  r13 {:016x}  r14 {:016x}",
+        frame.r12, frame.r13, frame.r14
+    )?;
+    writeln!(out, "r15 {:016x}  rip {:016x}", frame.r15, frame.rip)?;
+    writeln!(
+        out,
+        "rflags {:08x}  cs {:04x}  ss {:04x}",
+        frame.rflags, frame.cs, frame.ss
+    )?;
+    writeln!(out, "cr0 {:?}", Cr0::read())?;
+    writeln!(out, "cr2 {:?}", Cr2::read())?;
+    writeln!(out, "cr3 {:?}", Cr3::read().0.start_address())?;
+    writeln!(out, "cr4 {:?}", Cr4::read())
+}
+
+fn set_register(
+    out: &mut impl Write,
+    frame: &mut TrapFrame,
+    name: Option<&str>,
+    value: Option<&str>,
+) -> fmt::Result {
+    let value = match value.and_then(parse_number) {
+        Some(value) => value,
+        None => return writeln!(out, "usage: set <reg> <value>"),
+    };
+
+    let register = match name.unwrap_or("") {
+        "rax" => &mut frame.rax,
+        "rbx" => &mut frame.rbx,
+        "rcx" => &mut frame.rcx,
+        "rdx" => &mut frame.rdx,
+        "rsi" => &mut frame.rsi,
+        "rdi" => &mut frame.rdi,
+        "rbp" => &mut frame.rbp,
+        "r8" => &mut frame.r8,
+        "r9" => &mut frame.r9,
+        "r10" => &mut frame.r10,
+        "r11" => &mut frame.r11,
+        "r12" => &mut frame.r12,
+        "r13" => &mut frame.r13,
+        "r14" => &mut frame.r14,
+        "r15" => &mut frame.r15,
+        "rip" => &mut frame.rip,
+        "rflags" => &mut frame.rflags,
+        other => return writeln!(out, "cannot set register '{}'", other),
+    };
+    *register = value;
+    Ok(())
+}
+
+fn dump_memory(out: &mut impl Write, addr: Option<&str>, len: Option<&str>) -> fmt::Result {
+    let addr = match addr.and_then(parse_number) {
+        Some(addr) => addr,
+        None => return writeln!(out, "usage: x <addr> [len]"),
+    };
+    let len = len.and_then(parse_number).unwrap_or(64);
+
+    if !range_is_mapped(addr, len) {
+        return writeln!(
+            out,
+            "{:#x}..{:#x} is not mapped",
+            addr,
+            addr.wrapping_add(len)
+        );
+    }
+
+    for line_start in (addr..addr + len).step_by(16) {
+        let line_len = (addr + len - line_start).min(16);
+        let bytes =
+            unsafe { core::slice::from_raw_parts(line_start as *const u8, line_len as usize) };
+
+        write!(out, "{:016x}: ", line_start)?;
+        for byte in bytes {
+            write!(out, "{:02x} ", byte)?;
+        }
+        for _ in line_len..16 {
+            write!(out, "   ")?;
+        }
+        write!(out, "|")?;
+        for &byte in bytes {
+            let c = if (0x20..0x7f).contains(&byte) {
+                byte as char
+            } else {
+                '.'
+            };
+            write!(out, "{}", c)?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+fn write_memory<'a>(
+    out: &mut impl Write,
+    addr: Option<&str>,
+    bytes: impl Iterator<Item = &'a str> + Clone,
+) -> fmt::Result {
+    let addr = match addr.and_then(parse_number) {
+        Some(addr) => addr,
+        None => return writeln!(out, "usage: w <addr> <byte>..."),
+    };
+
+    // Validate everything before writing anything.
+    let mut count = 0;
+    for byte in bytes.clone() {
+        match parse_number(byte) {
+            Some(value) if value <= 0xff => count += 1,
+            _ => return writeln!(out, "'{}' is not a byte", byte),
+        }
+    }
+    if !range_is_mapped(addr, count) {
+        return writeln!(
+            out,
+            "{:#x}..{:#x} is not mapped",
+            addr,
+            addr.wrapping_add(count)
+        );
+    }
+
+    for (i, byte) in bytes.filter_map(parse_number).enumerate() {
+        // Code is read-only once the kernel is protected.
+        unsafe { super::gdb::poke(addr + i as u64, byte as u8) };
+    }
+    writeln!(out, "wrote {} bytes", count)
+}
+
+fn watchpoint_command<'a>(
+    out: &mut impl Write,
+    mut args: impl Iterator<Item = &'a str>,
+) -> fmt::Result {
+    match args.next() {
+        None => {
+            for slot in 0..WATCHPOINT_COUNT {
+                match watchpoint::get(slot) {
+                    Some(wp) => writeln!(
+                        out,
+                        "{}: {:?} {} bytes at {:?}",
+                        slot, wp.kind, wp.len, wp.addr
+                    )?,
+                    None => writeln!(out, "{}: unused", slot)?,
+                }
+            }
+            Ok(())
+        }
+        Some("set") => {
+            let addr = args.next().and_then(parse_number).map(VirtAddr::try_new);
+            let kind = match args.next() {
+                Some("x") => Some(WatchKind::Execute),
+                Some("w") => Some(WatchKind::Write),
+                Some("rw") => Some(WatchKind::Access),
+                _ => None,
+            };
+            let len = args.next().and_then(parse_number).unwrap_or(1) as usize;
+
+            match (addr, kind) {
+                (Some(Ok(addr)), Some(kind)) => {
+                    match watchpoint::set_any(Watchpoint { addr, kind, len }) {
+                        Ok(slot) => writeln!(out, "watchpoint {} set", slot),
+                        Err(err) => writeln!(out, "cannot set watchpoint: {:?}", err),
+                    }
+                }
+                _ => writeln!(out, "usage: wp set <addr> <x|w|rw> [len]"),
+            }
+        }
+        Some("clear") => match args.next().and_then(parse_number) {
+            Some(slot) => match watchpoint::clear(slot as usize) {
+                Ok(()) => writeln!(out, "watchpoint {} cleared", slot),
+                Err(err) => writeln!(out, "cannot clear watchpoint: {:?}", err),
+            },
+            None => writeln!(out, "usage: wp clear <slot>"),
+        },
+        Some(_) => writeln!(out, "usage: wp [set <addr> <x|w|rw> [len] | clear <slot>]"),
+    }
+}
+
+/// Print the entry for `addr` at each level of the active page tables.
+fn page_walk(out: &mut impl Write, addr: u64) -> fmt::Result {
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return writeln!(out, "{:#x} is not canonical", addr),
+    };
+
+    let mut result = Ok(());
+    let mapping = memory::walk_entries(addr, |level, index, entry| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "P{} [{:3}] -> {:#x} {:?}",
+                level,
+                index,
+                entry.addr().as_u64(),
+                entry.flags()
+            );
+        }
+    });
+    result?;
+
+    match mapping {
+        Some(mapping) => writeln!(
+            out,
+            "{:?} -> {:?}, {}KiB page\n{:?}",
+            addr,
+            mapping.phys_addr,
+            mapping.page_size() / 1024,
+            mapping.flags
+        ),
+        None => writeln!(out, "{:?} is not mapped", addr),
+    }
+}
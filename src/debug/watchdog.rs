@@ -0,0 +1,289 @@
+//! A soft lockup watchdog.
+//!
+//! The watchdog follows two heartbeats: the timer tick, and the executor
+//! polling tasks. If either stops for longer than the timeout, it dumps the
+//! interrupted RIP, a backtrace and the state of the kernel's locks, then
+//! exits QEMU with a failure code.
+//!
+//! While the APICs are in use, PIT channel 0 is free and is routed through
+//! the I/O APIC as an NMI, `NMI_HZ` times a second. An NMI gets through with
+//! interrupts disabled, so this catches code spinning with interrupts off,
+//! which is exactly what stops the tick. The PICs cannot raise an NMI, so
+//! with them the checks run from a kernel timer instead, which only catches
+//! a stalled executor.
+
+use super::backtrace::Backtrace;
+use crate::interrupts::apic;
+use crate::time::{pit, timer};
+use crate::{crash, sync, QemuExitCode};
+use conquer_once::spin::OnceCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Rate of the watchdog NMI.
+pub const NMI_HZ: u32 = 20;
+
+/// Timeout used by the kernel, well below the test timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Period of the checks when they run from a kernel timer.
+const TIMER_PERIOD: Duration = Duration::from_millis(100);
+
+/// The IRQ line of PIT channel 0.
+const PIT_IRQ: u8 = 0;
+
+/// How the watchdog is checking for lockups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// From the PIT, delivered as an NMI through the I/O APIC.
+    Nmi = 1,
+    /// From a kernel timer.
+    Timer = 2,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Nmi => write!(f, "NMI"),
+            Mode::Timer => write!(f, "kernel timer"),
+        }
+    }
+}
+
+/// The heartbeat that stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lockup {
+    TimerTick,
+    Executor,
+}
+
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lockup::TimerTick => write!(f, "timer tick"),
+            Lockup::Executor => write!(f, "executor"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// `start` has already been called.
+    AlreadyStarted,
+    /// The timeout is shorter than a millisecond.
+    InvalidTimeout,
+    /// The kernel timer for the checks could not be armed.
+    NoTimer,
+    /// A lockup handler has already been set.
+    HandlerAlreadySet,
+}
+
+/// Called after a lockup has been reported, instead of exiting QEMU.
+pub type LockupHandler = fn(Lockup) -> !;
+
+/// The `Mode` the watchdog was started in, or 0.
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Whether lockups are being checked for.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of live `Suspended` guards.
+static SUSPENDED: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLER: OnceCell<LockupHandler> = OnceCell::uninit();
+
+static EXECUTOR_RUNNING: AtomicBool = AtomicBool::new(false);
+static EXECUTOR_BEATS: AtomicU64 = AtomicU64::new(0);
+
+static TICK: Heartbeat = Heartbeat::new();
+static EXECUTOR: Heartbeat = Heartbeat::new();
+
+static CHECK_TIMER: timer::KernelTimer = timer::KernelTimer::new(timer_check);
+
+/// A heartbeat as last seen by the checks.
+struct Heartbeat {
+    last: AtomicU64,
+    stalled_ms: AtomicU64,
+}
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Heartbeat {
+            last: AtomicU64::new(0),
+            stalled_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the current beat count, `elapsed_ms` after the last check.
+    /// Returns how long the count has not changed for.
+    fn update(&self, beats: u64, elapsed_ms: u64) -> u64 {
+        if self.last.swap(beats, Ordering::Relaxed) != beats {
+            self.stalled_ms.store(0, Ordering::Relaxed);
+            0
+        } else {
+            self.stalled_ms.fetch_add(elapsed_ms, Ordering::Relaxed) + elapsed_ms
+        }
+    }
+
+    fn reset(&self) {
+        self.stalled_ms.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Start checking for lockups of longer than `timeout`.
+///
+/// The NMI is only available once `interrupts::init_apic` has switched to
+/// the APICs.
+pub fn start(timeout: Duration) -> Result<Mode, WatchdogError> {
+    let timeout_ms = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+    if timeout_ms == 0 {
+        return Err(WatchdogError::InvalidTimeout);
+    }
+    if MODE.load(Ordering::SeqCst) != 0 {
+        return Err(WatchdogError::AlreadyStarted);
+    }
+    TIMEOUT_MS.store(timeout_ms, Ordering::SeqCst);
+    ACTIVE.store(true, Ordering::SeqCst);
+
+    // The mode is set before the first NMI can arrive.
+    MODE.store(Mode::Nmi as u8, Ordering::SeqCst);
+    if apic::route_as_nmi(PIT_IRQ) {
+        pit::start_periodic(NMI_HZ);
+        return Ok(Mode::Nmi);
+    }
+
+    MODE.store(Mode::Timer as u8, Ordering::SeqCst);
+    CHECK_TIMER.arm_periodic(TIMER_PERIOD).map_err(|_| {
+        MODE.store(0, Ordering::SeqCst);
+        WatchdogError::NoTimer
+    })?;
+    Ok(Mode::Timer)
+}
+
+/// Stop checking for lockups for good, such as once the kernel has
+/// panicked and halts with interrupts disabled.
+pub fn stop() {
+    ACTIVE.store(false, Ordering::SeqCst);
+}
+
+/// Call `handler` after reporting a lockup, instead of exiting QEMU.
+pub fn set_handler(handler: LockupHandler) -> Result<(), WatchdogError> {
+    HANDLER
+        .try_init_once(|| handler)
+        .map_err(|_| WatchdogError::HandlerAlreadySet)
+}
+
+/// Pauses the watchdog until dropped.
+pub struct Suspended(());
+
+impl Drop for Suspended {
+    fn drop(&mut self) {
+        SUSPENDED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Pause the watchdog, for code that stops the heartbeats on purpose, such
+/// as a debugger waiting for commands with interrupts disabled.
+pub fn suspend() -> Suspended {
+    SUSPENDED.fetch_add(1, Ordering::SeqCst);
+    Suspended(())
+}
+
+/// Mark the executor as running or not. A stopped executor is not a lockup.
+pub fn set_executor_running(running: bool) {
+    EXECUTOR_RUNNING.store(running, Ordering::Relaxed);
+}
+
+/// Record that the executor made progress.
+pub fn executor_heartbeat() {
+    EXECUTOR_BEATS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handle an NMI without a reason in system control port B. Returns false
+/// if the watchdog does not raise NMIs, so it cannot have been ours.
+///
+/// `backtrace` is that of the interrupted code, see
+/// `Backtrace::interrupted`.
+pub(crate) fn handle_nmi(stack_frame: &InterruptStackFrame, backtrace: Backtrace) -> bool {
+    if MODE.load(Ordering::SeqCst) != Mode::Nmi as u8 {
+        return false;
+    }
+    check(u64::from(1000 / NMI_HZ), Some((stack_frame, backtrace)));
+    true
+}
+
+fn timer_check() {
+    check(TIMER_PERIOD.as_millis() as u64, None);
+}
+
+/// Update both heartbeats and report a lockup if either has timed out.
+///
+/// `interrupted` is the stack frame and backtrace of the interrupted code,
+/// when the check runs from the NMI.
+fn check(elapsed_ms: u64, interrupted: Option<(&InterruptStackFrame, Backtrace)>) {
+    if !ACTIVE.load(Ordering::SeqCst) || SUSPENDED.load(Ordering::SeqCst) != 0 {
+        TICK.reset();
+        EXECUTOR.reset();
+        return;
+    }
+    let timeout = TIMEOUT_MS.load(Ordering::SeqCst);
+
+    let tick = TICK.update(timer::ticks(), elapsed_ms);
+    let executor = if EXECUTOR_RUNNING.load(Ordering::Relaxed) {
+        EXECUTOR.update(EXECUTOR_BEATS.load(Ordering::Relaxed), elapsed_ms)
+    } else {
+        EXECUTOR.reset();
+        0
+    };
+
+    if tick >= timeout {
+        lockup(Lockup::TimerTick, tick, interrupted);
+    } else if executor >= timeout {
+        lockup(Lockup::Executor, executor, interrupted);
+    }
+}
+
+/// Report the lockup, then hand over to the handler or exit QEMU.
+fn lockup(
+    lockup: Lockup,
+    stalled_ms: u64,
+    interrupted: Option<(&InterruptStackFrame, Backtrace)>,
+) -> ! {
+    ACTIVE.store(false, Ordering::SeqCst);
+
+    // Taken before the output locks are broken, so it shows who held them.
+    let locks = sync::report();
+
+    // The interrupted code is stuck, so it will never release the locks.
+    let mut out = unsafe { crash::take_output() };
+
+    // Nothing can be done if writing fails, so the results are ignored.
+    let _ = writeln!(out, "\n******** SOFT LOCKUP ********");
+    let _ = writeln!(out, "The {} made no progress for {}ms", lockup, stalled_ms);
+    // From a kernel timer the interrupted frame is not known, but the
+    // backtrace runs through the interrupt handlers into it.
+    let (rip, backtrace) = match interrupted {
+        Some((stack_frame, backtrace)) => (Some(stack_frame.instruction_pointer), backtrace),
+        None => (None, Backtrace::current()),
+    };
+    let _ = match rip {
+        Some(rip) => writeln!(out, "RIP: {:#x}", rip.as_u64()),
+        None => writeln!(out, "RIP: unknown, see the interrupted frames below"),
+    };
+    let _ = write!(out, "Backtrace:\n{}", backtrace);
+    let _ = writeln!(out, "Locks:\n{}", locks);
+    let _ = writeln!(out, "*****************************");
+
+    match HANDLER.try_get().ok() {
+        Some(handler) => handler(lockup),
+        None => {
+            crate::exit_qemu(QemuExitCode::Failed);
+            crate::hlt_loop();
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Hardware watchpoints using the debug registers.
+//!
+//! DR0 to DR3 hold the watched addresses and DR7 enables them and sets what
+//! kind of access triggers each one. A hit raises a debug exception, which
+//! reports the hit and passes it on to the selected debugger.
+//!
+//! The state lives entirely in the debug registers, so it can be read from
+//! the debug exception without taking any locks.
+
+use super::backtrace::Backtrace;
+use super::TrapFrame;
+use crate::crash::RawSerial;
+use core::fmt::Write;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber, Dr0,
+    Dr1, Dr2, Dr3, Dr6Flags, Dr7, Dr7Flags,
+};
+use x86_64::VirtAddr;
+
+/// Number of hardware watchpoints provided by the CPU.
+pub const WATCHPOINT_COUNT: usize = 4;
+
+/// The kind of access a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Writing to the watched bytes.
+    Write,
+    /// Reading or writing the watched bytes.
+    Access,
+}
+
+impl WatchKind {
+    fn condition(self) -> BreakpointCondition {
+        match self {
+            WatchKind::Execute => BreakpointCondition::InstructionExecution,
+            WatchKind::Write => BreakpointCondition::DataWrites,
+            WatchKind::Access => BreakpointCondition::DataReadsWrites,
+        }
+    }
+}
+
+/// A hardware watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: VirtAddr,
+    pub kind: WatchKind,
+    /// Number of watched bytes: 1, 2, 4 or 8. Must be 1 for `Execute`.
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// The slot is not one of the four debug address registers.
+    InvalidSlot,
+    /// The length is not 1, 2, 4 or 8, or not 1 for an execute watchpoint.
+    InvalidLength,
+    /// The address is not aligned to the length.
+    Unaligned,
+    /// All four debug address registers are in use.
+    NoFreeSlot,
+}
+
+fn register_number(slot: usize) -> Result<DebugAddressRegisterNumber, WatchpointError> {
+    DebugAddressRegisterNumber::new(slot as u8)
+        .filter(|_| slot < WATCHPOINT_COUNT)
+        .ok_or(WatchpointError::InvalidSlot)
+}
+
+fn read_address(n: DebugAddressRegisterNumber) -> u64 {
+    match n {
+        DebugAddressRegisterNumber::Dr0 => Dr0::read(),
+        DebugAddressRegisterNumber::Dr1 => Dr1::read(),
+        DebugAddressRegisterNumber::Dr2 => Dr2::read(),
+        DebugAddressRegisterNumber::Dr3 => Dr3::read(),
+    }
+}
+
+fn write_address(n: DebugAddressRegisterNumber, addr: u64) {
+    match n {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr),
+    }
+}
+
+/// Set the watchpoint in `slot`, replacing whatever was there.
+pub fn set(slot: usize, watchpoint: Watchpoint) -> Result<(), WatchpointError> {
+    let n = register_number(slot)?;
+    let size = BreakpointSize::new(watchpoint.len).ok_or(WatchpointError::InvalidLength)?;
+    if watchpoint.kind == WatchKind::Execute && watchpoint.len != 1 {
+        return Err(WatchpointError::InvalidLength);
+    }
+    if !watchpoint.addr.is_aligned(watchpoint.len as u64) {
+        return Err(WatchpointError::Unaligned);
+    }
+
+    // A debug exception between the writes would see half a watchpoint.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut dr7 = Dr7::read();
+        dr7.remove_flags(Dr7Flags::local_breakpoint_enable(n));
+        Dr7::write(dr7);
+
+        write_address(n, watchpoint.addr.as_u64());
+        dr7.set_condition(n, watchpoint.kind.condition());
+        dr7.set_size(n, size);
+        dr7.insert_flags(Dr7Flags::local_breakpoint_enable(n));
+        Dr7::write(dr7);
+    });
+    Ok(())
+}
+
+/// Set a watchpoint in the first free slot and return the slot.
+pub fn set_any(watchpoint: Watchpoint) -> Result<usize, WatchpointError> {
+    let slot = (0..WATCHPOINT_COUNT)
+        .find(|&slot| get(slot).is_none())
+        .ok_or(WatchpointError::NoFreeSlot)?;
+    set(slot, watchpoint)?;
+    Ok(slot)
+}
+
+/// Disable the watchpoint in `slot`.
+pub fn clear(slot: usize) -> Result<(), WatchpointError> {
+    let n = register_number(slot)?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut dr7 = Dr7::read();
+        dr7.remove_flags(Dr7Flags::local_breakpoint_enable(n));
+        Dr7::write(dr7);
+        write_address(n, 0);
+    });
+    Ok(())
+}
+
+/// Returns the watchpoint in `slot`, if it is enabled.
+pub fn get(slot: usize) -> Option<Watchpoint> {
+    let n = register_number(slot).ok()?;
+    let dr7 = Dr7::read();
+    if !dr7.flags().contains(Dr7Flags::local_breakpoint_enable(n)) {
+        return None;
+    }
+
+    let kind = match dr7.condition(n) {
+        BreakpointCondition::InstructionExecution => WatchKind::Execute,
+        BreakpointCondition::DataWrites => WatchKind::Write,
+        _ => WatchKind::Access,
+    };
+    let len = match dr7.size(n) {
+        BreakpointSize::Length1B => 1,
+        BreakpointSize::Length2B => 2,
+        BreakpointSize::Length4B => 4,
+        BreakpointSize::Length8B => 8,
+    };
+    Some(Watchpoint {
+        addr: VirtAddr::new_truncate(read_address(n)),
+        kind,
+        len,
+    })
+}
+
+/// Returns the slots whose conditions are reported as met in `dr6`.
+pub fn triggered(dr6: Dr6Flags) -> impl Iterator<Item = usize> {
+    (0..WATCHPOINT_COUNT).filter(move |&slot| match register_number(slot) {
+        Ok(n) => dr6.contains(Dr6Flags::trap(n)),
+        Err(_) => false,
+    })
+}
+
+/// Report a watchpoint hit on the serial port, with a backtrace of the code
+/// that hit it.
+pub fn report(frame: &TrapFrame, dr6: Dr6Flags) {
+    // The code that hit the watchpoint may hold the lock around `SERIAL1`,
+    // and it runs again once the trap returns.
+    let mut out = RawSerial::com1();
+
+    for slot in triggered(dr6) {
+        let watchpoint = match get(slot) {
+            Some(watchpoint) => watchpoint,
+            None => continue,
+        };
+
+        let _ = writeln!(
+            out,
+            "WATCHPOINT {} HIT: {:?} of {} bytes at {:?}",
+            slot,
+            watchpoint.kind,
+            watchpoint.len,
+            watchpoint.addr
+        );
+        // Data watchpoints trap after the access, execute watchpoints fault
+        // before the instruction runs.
+        let _ = match watchpoint.kind {
+            WatchKind::Execute => writeln!(out, "Instruction at {:#x}", frame.rip),
+            _ => writeln!(out, "Instruction before {:#x}", frame.rip),
+        };
+    }
+    let _ = writeln!(out, "Backtrace:\n{}", Backtrace::from_frame_pointer(frame.rbp));
+}
@@ -1,12 +1,19 @@
-use crate::hlt_loop;
+use crate::{crash, debug};
 use crate::{gdt, print, println};
+use core::fmt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use crate::sync::IrqSafeMutex;
 use x86_64::structures::idt::{
-    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    Entry, HandlerFuncWithErrCode, InterruptDescriptorTable, InterruptStackFrame,
+    PageFaultErrorCode, SelectorErrorCode,
 };
 
+pub mod apic;
+pub mod deferred;
+pub mod irq;
+pub mod stats;
+
 lazy_static! {
     /// Define the reference for the IDT.
     /// We use lazy static as rust compiler doesn't like normal
@@ -15,9 +22,42 @@ lazy_static! {
 
         let mut idt = InterruptDescriptorTable::new();
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
+
+        // The x86_64 crate keeps vector 21 (#CP) among its reserved entries,
+        // so it has to be reached through a raw pointer. Every entry has the
+        // same layout, so indexing the table as an array is sound.
+        unsafe {
+            let entries = &mut idt as *mut InterruptDescriptorTable
+                as *mut Entry<HandlerFuncWithErrCode>;
+            (*entries.add(usize::from(crash::CONTROL_PROTECTION.vector)))
+                .set_handler_fn(control_protection_handler);
+        }
+
+        // The debugger needs every register of the interrupted code, so #DB
+        // and #BP use the entry stubs from the debug module. The stubs must
+        // follow the interrupt calling convention, hence the unsafe block.
+        unsafe {
+            idt.debug.set_handler_addr(debug::debug_exception_entry_addr());
+            idt.breakpoint.set_handler_addr(debug::breakpoint_entry_addr());
+        }
 
         // This is unsafe as the used index MUST be valid, otherwise the
         // exception may not trigger or be a different exception than desired.
@@ -25,10 +65,14 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-        .set_handler_fn(keyboard_interrupt_handler);
+
+        // Hardware interrupts all go through the dispatch stubs, which call
+        // whatever handlers have been registered for the line.
+        for (irq, &stub) in irq::STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(apic::spurious_interrupt_handler);
         idt
     };
 }
@@ -39,45 +83,390 @@ pub fn init_idt() {
 
     IDT.load();
 
+    register_builtin(InterruptIndex::Timer, timer_interrupt_handler);
+    register_builtin(InterruptIndex::Keyboard, keyboard_interrupt_handler);
+
     println!("[ok]");
 }
 
+/// Register one of the kernel's own IRQ handlers. It is already registered
+/// if `init_idt` runs again, which only reloads the IDT.
+fn register_builtin(index: InterruptIndex, handler: irq::IrqHandler) {
+    match irq::register_irq(index.irq(), handler) {
+        Ok(()) | Err(irq::IrqError::AlreadyRegistered) => {}
+        Err(err) => panic!("failed to register the {:?} interrupt: {:?}", index, err),
+    }
+}
+
+/// Initialise the PICs, masking every line that has no handler registered.
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() };
+
+    crate::time::pit::start_periodic(crate::time::timer::TICK_HZ);
+
+    irq::apply_masks();
+}
+
+/// Switch from the PICs to the local and I/O APICs, if the machine has them.
+/// Returns whether the switch was made.
+///
+/// The APICs are found through the ACPI tables and reached through the
+/// physical memory mapping, so this must be called after `memory::init`.
+pub fn init_apic() -> bool {
+    irq::switch_to_apic()
+}
+
 // *****************************************
 //
 // CPU Exceptions
 //
 // *****************************************
 
-/// exception handler for breakpoints
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Exception handler for divide errors, raised on division by zero or when
+/// the quotient does not fit in the destination.
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    crash::report(
+        crash::DIVIDE_ERROR,
+        format_args!("Division by zero or quotient too large"),
+        &stack_frame,
+    );
+}
+
+/// Handler for non-maskable interrupts.
+///
+/// The NMI reason is read from system control port B. Parity and channel
+/// check errors are reported, and execution continues. NMIs without a reason
+/// come from the lockup watchdog.
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    stats::record(crash::NON_MASKABLE_INTERRUPT.vector);
+
+    let mut port: Port<u8> = Port::new(0x61);
+    let reason = unsafe { port.read() };
+
+    // Taken here, while this handler's frame is the innermost one.
+    let backtrace = debug::backtrace::Backtrace::interrupted();
+    if reason & (0b11 << 6) == 0 && debug::watchdog::handle_nmi(&stack_frame, backtrace) {
+        return;
+    }
+
+    println!(
+        "NON-MASKABLE INTERRUPT\nMemory parity error: {}\nI/O channel check: {}\n{:#?}",
+        reason & (1 << 7) != 0,
+        reason & (1 << 6) != 0,
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    stats::record(crash::OVERFLOW.vector);
     println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
+/// Exception handler for a failed `BOUND` instruction.
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    crash::report(
+        crash::BOUND_RANGE_EXCEEDED,
+        format_args!("Index outside of the checked bounds"),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for invalid opcodes. The bytes at the faulting
+/// instruction are printed to help identify it.
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    // The instruction was fetched before it was decoded, so its bytes are
+    // known to be mapped.
+    let rip: *const [u8; 8] = stack_frame.instruction_pointer.as_ptr();
+    let opcode = unsafe { rip.read_unaligned() };
+
+    crash::report(
+        crash::INVALID_OPCODE,
+        format_args!("Opcode bytes at RIP: {:02x?}", opcode),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for FPU instructions used while the FPU is unavailable.
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::registers::control::Cr0;
+
+    crash::report(
+        crash::DEVICE_NOT_AVAILABLE,
+        format_args!("FPU instruction with CR0 {:?}", Cr0::read()),
+        &stack_frame,
+    );
+}
+
 /// exception handler for double faults
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\nError Code: {:#?}", stack_frame, _error_code);
+    // The error code of a double fault is always zero.
+    crash::report(
+        crash::DOUBLE_FAULT,
+        format_args!("An exception occurred while delivering another"),
+        &stack_frame,
+    );
 }
 
-/// Exception handler for page faults.
+/// Exception handler for an invalid task state segment.
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    crash::report(
+        crash::INVALID_TSS,
+        format_args!("{}", SelectorError(error_code)),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for loading a segment that is not present.
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    crash::report(
+        crash::SEGMENT_NOT_PRESENT,
+        format_args!("{}", SelectorError(error_code)),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for stack segment faults.
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    crash::report(
+        crash::STACK_SEGMENT_FAULT,
+        format_args!("{}", SelectorError(error_code)),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for general protection faults.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    crash::report(
+        crash::GENERAL_PROTECTION_FAULT,
+        format_args!("{}", SelectorError(error_code)),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for page faults. Faults in lazy areas are resolved by
+/// mapping the page, anything else crashes.
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
     //CR2 is set on page fault and contains address that caused it
-    println!("Accessed Address:{:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let addr = Cr2::read();
+    if crate::memory::vmm::handle_page_fault(addr, error_code) {
+        stats::record(crash::PAGE_FAULT.vector);
+        return;
+    }
+
+    crash::report(
+        crash::PAGE_FAULT,
+        format_args!("Accessed Address: {:?}\nError Code: {:?}", addr, error_code),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for unmasked x87 floating point errors. The status
+/// word tells which error was raised.
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let status: u16;
+    unsafe {
+        core::arch::asm!("fnstsw ax", out("ax") status, options(nomem, nostack));
+    }
+
+    crash::report(
+        crash::X87_FLOATING_POINT,
+        format_args!("{}", X87Status(status)),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for unaligned memory accesses with alignment checking
+/// enabled.
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) {
+    crash::report(
+        crash::ALIGNMENT_CHECK,
+        format_args!("Unaligned memory access"),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for machine checks. The machine check banks are read to
+/// report which part of the hardware detected the error.
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    crash::report(
+        crash::MACHINE_CHECK,
+        format_args!("{}", MachineCheckBanks),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for unmasked SIMD floating point errors.
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::registers::mxcsr;
+
+    crash::report(
+        crash::SIMD_FLOATING_POINT,
+        format_args!("MXCSR: {:?}", mxcsr::read()),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for EPT violations reported to a guest.
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    crash::report(
+        crash::VIRTUALIZATION,
+        format_args!("EPT violation"),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for control flow protection violations.
+extern "x86-interrupt" fn control_protection_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let cause = match error_code & 0x7fff {
+        1 => "NEAR-RET",
+        2 => "FAR-RET/IRET",
+        3 => "ENDBRANCH",
+        4 => "RSTORSSP",
+        5 => "SETSSBSY",
+        _ => "unknown",
+    };
+
+    crash::report(
+        crash::CONTROL_PROTECTION,
+        format_args!(
+            "Cause: {}\nDuring enclave execution: {}",
+            cause,
+            error_code & (1 << 15) != 0
+        ),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for SEV-ES events that need the hypervisor.
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    crash::report(
+        crash::VMM_COMMUNICATION,
+        format_args!("VMEXIT code: {:#x}", error_code),
+        &stack_frame,
+    );
+}
+
+/// Exception handler for security exceptions.
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    crash::report(
+        crash::SECURITY,
+        format_args!("Error Code: {:#x}", error_code),
+        &stack_frame,
+    );
+}
+
+/// Decodes the error code pushed by exceptions that reference a segment
+/// selector (#TS, #NP, #SS and #GP).
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match SelectorErrorCode::new(self.0) {
+            Some(selector) if !selector.is_null() => write!(
+                f,
+                "Selector: index {} in the {:?}, external event: {}",
+                selector.index(),
+                selector.descriptor_table(),
+                selector.external()
+            ),
+            Some(_) => write!(f, "Not caused by a segment selector"),
+            None => write!(f, "Error Code: {:#x}", self.0),
+        }
+    }
+}
+
+/// Decodes the exception flags of the x87 status word.
+struct X87Status(u16);
+
+impl fmt::Display for X87Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const FLAGS: [&str; 7] = [
+            "invalid operation",
+            "denormal operand",
+            "zero divide",
+            "overflow",
+            "underflow",
+            "precision",
+            "stack fault",
+        ];
+
+        write!(f, "FPU status word: {:#06x}", self.0)?;
+        for (bit, name) in FLAGS.iter().enumerate() {
+            if self.0 & (1 << bit) != 0 {
+                write!(f, "\n  {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the machine check MSRs and prints every bank holding a valid error.
+struct MachineCheckBanks;
+
+impl fmt::Display for MachineCheckBanks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use x86_64::registers::model_specific::Msr;
+
+        const IA32_MCG_CAP: u32 = 0x179;
+        const IA32_MCG_STATUS: u32 = 0x17a;
+        const IA32_MC0_STATUS: u32 = 0x401;
+        const STATUS_VALID: u64 = 1 << 63;
+        const STATUS_ADDRV: u64 = 1 << 58;
+
+        let (cap, status) = unsafe {
+            (
+                Msr::new(IA32_MCG_CAP).read(),
+                Msr::new(IA32_MCG_STATUS).read(),
+            )
+        };
+        write!(f, "MCG_STATUS: {:#x}", status)?;
+
+        // Each bank has four registers: CTL, STATUS, ADDR and MISC.
+        for bank in 0..(cap & 0xff) as u32 {
+            let bank_status = unsafe { Msr::new(IA32_MC0_STATUS + bank * 4).read() };
+            if bank_status & STATUS_VALID == 0 {
+                continue;
+            }
+
+            write!(f, "\nBank {}: status {:#x}", bank, bank_status)?;
+            if bank_status & STATUS_ADDRV != 0 {
+                let addr = unsafe { Msr::new(IA32_MC0_STATUS + bank * 4 + 1).read() };
+                write!(f, " address {:#x}", addr)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 // *****************************************
@@ -104,8 +493,8 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 /// Set static mutex for pics. Wrong offsets can cause undefined behaviour
 /// so they are wrapped in an unsafe block.
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 /// This contains the indexes of the interrupts that will hit our PIC.
 #[derive(Debug, Clone, Copy)]
@@ -122,29 +511,21 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// The IRQ line of the interrupt, as used by `irq::register_irq`.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
-/// This function handles the timer intrrupts that occur. It incorporates the
-/// notify end of interrupt function so that the PIC can continue to recieve
-/// interrupts after the first one.
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame,
-) {
-    // TODO implement this in a way that only requires a single unsafe block
-    // around an abstracted function.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+/// This function handles the timer intrrupts that occur, running any kernel
+/// timers that are due. The end of interrupt is signalled by the dispatch
+/// stub.
+fn timer_interrupt_handler(_irq: u8) {
+    crate::time::timer::tick();
 }
 
 /// This function handles the keyboard interrupts.
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame,
-) {
+fn keyboard_interrupt_handler(_irq: u8) {
 
     use x86_64::instructions::port::Port;
 
@@ -152,19 +533,20 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
-    crate::task::keyboard::add_scancode(scancode);
+    let break_into_debugger = scancode == debug::HOTKEY_SCANCODE
+        && debug::backend() != debug::Backend::None;
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    if break_into_debugger {
+        // The end of interrupt is sent once execution resumes, so the
+        // keyboard stays quiet while the debugger has control.
+        x86_64::instructions::interrupts::int3();
+    } else {
+        // Queueing the scancode can print a warning, so it is left to the
+        // deferred work task. A dropped scancode is counted there.
+        let _ = deferred::defer(deferred::Work::new(queue_scancode, usize::from(scancode)));
     }
 }
 
-extern "x86-interrupt" fn _RTC_interrupt_handler(
-    _stack_frame: InterruptStackFrame,
-) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::RTC.as_u8());
-    }
+fn queue_scancode(scancode: usize) {
+    crate::task::keyboard::add_scancode(scancode as u8);
 }
@@ -0,0 +1,351 @@
+//! Local APIC and I/O APIC support.
+//!
+//! When the CPU has a local APIC and the firmware describes an I/O APIC in
+//! the MADT, the legacy PICs are masked and the IRQ lines are routed through
+//! the I/O APIC to the same vectors the PICs used, so registered handlers
+//! keep working unchanged. IRQ0 is driven by the local APIC timer instead of
+//! the PIT, at the same `TICK_HZ`.
+//!
+//! The registers are mapped uncached with `map_mmio`, so this can only be
+//! set up after `memory::install`.
+
+use super::irq::IRQ_LINES;
+use super::{stats, PIC_1_OFFSET};
+use crate::acpi::{self, IoApicEntry, MAX_IO_APICS};
+use crate::memory::{self, MmioRegion};
+use crate::sync::IrqSafeMutex;
+use crate::time::pit;
+use crate::time::timer::TICK_HZ;
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::__cpuid;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Vector for spurious local APIC interrupts. The low four bits must be set
+/// on older processors.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Size of the local APIC register page.
+const LAPIC_SIZE: usize = 0x1000;
+
+// Local APIC register offsets.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Size of the I/O APIC select and window registers.
+const IOAPIC_SIZE: usize = 0x20;
+
+// I/O APIC registers.
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_NMI: u32 = 0b100 << 8;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// The IRQ line driven by the local APIC timer.
+const TIMER_IRQ: u8 = 0;
+
+/// The local APIC registers, mapped once the APICs are in use.
+static LOCAL_APIC: OnceCell<MmioRegion> = OnceCell::uninit();
+
+/// Where each legacy IRQ line ends up at the I/O APICs.
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    /// Index of the I/O APIC in `IoApics::registers`.
+    io_apic: usize,
+    /// The redirection table entry on that I/O APIC.
+    entry: u32,
+    /// Redirection entry bits for the polarity and trigger mode.
+    mode: u32,
+}
+
+/// The I/O APICs and the routes of the IRQ lines through them.
+struct IoApics {
+    /// The registers of each I/O APIC in the MADT, in the same order.
+    registers: [Option<MmioRegion>; MAX_IO_APICS],
+    routes: [Option<Route>; IRQ_LINES as usize],
+}
+
+impl IoApics {
+    fn read(&self, route: &Route, register: u32) -> u32 {
+        self.registers[route.io_apic]
+            .as_ref()
+            .map_or(0, |io_apic| ioapic_read(io_apic, register))
+    }
+
+    fn write(&self, route: &Route, register: u32, value: u32) {
+        if let Some(io_apic) = &self.registers[route.io_apic] {
+            ioapic_write(io_apic, register, value);
+        }
+    }
+}
+
+const NO_REGION: Option<MmioRegion> = None;
+
+/// The I/O APICs, filled in by `init`. The lock also serialises access to
+/// their select and window registers, which must be used as a pair.
+static IO_APICS: IrqSafeMutex<IoApics> = IrqSafeMutex::new(
+    "apic::IO_APICS",
+    IoApics {
+        registers: [NO_REGION; MAX_IO_APICS],
+        routes: [None; IRQ_LINES as usize],
+    },
+);
+
+/// Returns whether the CPU has a local APIC, according to CPUID.
+pub fn is_supported() -> bool {
+    // `__cpuid` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    let features = unsafe { __cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+/// Returns whether interrupts are being delivered through the APICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_initialized()
+}
+
+fn lapic_read(register: usize) -> u32 {
+    LOCAL_APIC
+        .try_get()
+        .map_or(0, |local_apic| local_apic.read(register))
+}
+
+fn lapic_write(register: usize, value: u32) {
+    if let Ok(local_apic) = LOCAL_APIC.try_get() {
+        local_apic.write(register, value);
+    }
+}
+
+fn ioapic_read(io_apic: &MmioRegion, register: u32) -> u32 {
+    io_apic.write(IOAPIC_REGSEL, register);
+    io_apic.read(IOAPIC_WINDOW)
+}
+
+fn ioapic_write(io_apic: &MmioRegion, register: u32, value: u32) {
+    io_apic.write(IOAPIC_REGSEL, register);
+    io_apic.write(IOAPIC_WINDOW, value);
+}
+
+/// Convert MPS INTI flags into redirection entry bits. ISA interrupts are
+/// active high and edge triggered unless the flags say otherwise.
+fn redirection_mode(flags: u16) -> u32 {
+    let mut mode = 0;
+    if flags & 0b11 == 0b11 {
+        mode |= REDIRECTION_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        mode |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    mode
+}
+
+/// Find the I/O APIC handling `gsi`, returning its index and the
+/// redirection entry for the interrupt.
+fn find_io_apic(
+    entries: &[Option<IoApicEntry>; MAX_IO_APICS],
+    registers: &[Option<MmioRegion>; MAX_IO_APICS],
+    gsi: u32,
+) -> Option<(usize, u32)> {
+    entries
+        .iter()
+        .zip(registers)
+        .enumerate()
+        .find_map(|(index, (io_apic, registers))| {
+            let (io_apic, registers) = (io_apic.as_ref()?, registers.as_ref()?);
+            let count = ((ioapic_read(registers, IOAPIC_VERSION) >> 16) & 0xff) + 1;
+
+            let entry = gsi.checked_sub(io_apic.gsi_base)?;
+            (entry < count).then_some((index, entry))
+        })
+}
+
+/// Write the redirection entry for `route`, delivering `irq` to the local
+/// APIC with id `destination`.
+fn program_route(io_apics: &IoApics, route: &Route, irq: u8, destination: u8) {
+    let register = IOAPIC_REDIRECTION_TABLE + route.entry * 2;
+    let low = u32::from(PIC_1_OFFSET + irq) | route.mode | REDIRECTION_MASKED;
+
+    io_apics.write(route, register + 1, u32::from(destination) << 24);
+    io_apics.write(route, register, low);
+}
+
+/// Count the ticks of the local APIC timer over 10ms, measured with the
+/// PIT.
+fn calibrate_timer() -> u32 {
+    const CALIBRATION_HZ: u32 = 100;
+
+    pit::start_countdown(CALIBRATION_HZ);
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    pit::wait_countdown();
+
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+
+    (u64::from(elapsed) * u64::from(CALIBRATION_HZ) / u64::from(TICK_HZ)) as u32
+}
+
+/// Enable the local APIC and work out the I/O APIC routes of the IRQ lines.
+///
+/// Every route is left masked, and the local APIC timer is programmed but
+/// masked too. Returns false if there is no APIC or no MADT, or the
+/// registers cannot be mapped, in which case nothing is changed and the PICs
+/// stay in use.
+///
+/// Must be called with interrupts disabled.
+pub(super) fn init() -> bool {
+    if is_enabled() {
+        return true;
+    }
+    if !is_supported() {
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    let local_apic = match unsafe { memory::map_mmio(madt.local_apic_address, LAPIC_SIZE) } {
+        Ok(local_apic) => local_apic,
+        Err(_) => return false,
+    };
+    let mut registers = [NO_REGION; MAX_IO_APICS];
+    for (registers, io_apic) in registers.iter_mut().zip(&madt.io_apics) {
+        *registers = io_apic
+            .and_then(|io_apic| unsafe { memory::map_mmio(io_apic.address, IOAPIC_SIZE).ok() });
+    }
+    if registers.iter().all(Option::is_none) {
+        return false;
+    }
+
+    let mut io_apics = IO_APICS.lock();
+    let mut routes = [None; IRQ_LINES as usize];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        let irq_override = madt
+            .overrides
+            .iter()
+            .flatten()
+            .find(|o| usize::from(o.irq) == irq);
+        let (gsi, flags) = irq_override.map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+
+        *route = find_io_apic(&madt.io_apics, &registers, gsi).map(|(io_apic, entry)| Route {
+            io_apic,
+            entry,
+            mode: redirection_mode(flags),
+        });
+    }
+    io_apics.registers = registers;
+    io_apics.routes = routes;
+
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+    LOCAL_APIC.init_once(|| local_apic);
+
+    lapic_write(LAPIC_TASK_PRIORITY, 0);
+    lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    lapic_write(LAPIC_LVT_LINT1, LVT_NMI);
+    lapic_write(LAPIC_LVT_ERROR, LVT_MASKED);
+    lapic_write(
+        LAPIC_SPURIOUS,
+        LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+
+    let destination = (lapic_read(LAPIC_ID) >> 24) as u8;
+    for (irq, route) in io_apics.routes.iter().enumerate() {
+        if let Some(route) = route {
+            program_route(&io_apics, route, irq as u8, destination);
+        }
+    }
+
+    let ticks = calibrate_timer();
+    lapic_write(
+        LAPIC_LVT_TIMER,
+        u32::from(PIC_1_OFFSET + TIMER_IRQ) | LVT_TIMER_PERIODIC | LVT_MASKED,
+    );
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_TIMER_INITIAL, ticks.max(1));
+
+    true
+}
+
+/// Deliver the IRQ line `irq` as an NMI instead of through its vector, and
+/// unmask it at the I/O APIC. Returns false if the line has no route, which
+/// is always the case while the PICs are in use.
+///
+/// NMIs are edge triggered whatever the MADT says about the line. `set_masked`
+/// never touches the I/O APIC for IRQ0, so the PIT line can be taken over
+/// this way without disturbing the timer interrupt.
+pub(crate) fn route_as_nmi(irq: u8) -> bool {
+    let io_apics = IO_APICS.lock();
+    let route = match io_apics.routes.get(usize::from(irq)).copied().flatten() {
+        Some(route) => route,
+        None => return false,
+    };
+
+    let register = IOAPIC_REDIRECTION_TABLE + route.entry * 2;
+    io_apics.write(
+        &route,
+        register,
+        REDIRECTION_NMI | (route.mode & REDIRECTION_ACTIVE_LOW),
+    );
+    true
+}
+
+/// Mask or unmask an IRQ line at the I/O APIC, or the local APIC timer for
+/// IRQ0.
+///
+/// Must be called with interrupts disabled.
+pub(super) fn set_masked(irq: u8, masked: bool) {
+    let update = |value: u32, bit: u32| if masked { value | bit } else { value & !bit };
+
+    if irq == TIMER_IRQ {
+        lapic_write(
+            LAPIC_LVT_TIMER,
+            update(lapic_read(LAPIC_LVT_TIMER), LVT_MASKED),
+        );
+        return;
+    }
+
+    let io_apics = IO_APICS.lock();
+    if let Some(route) = io_apics.routes[usize::from(irq)] {
+        let register = IOAPIC_REDIRECTION_TABLE + route.entry * 2;
+        let low = io_apics.read(&route, register);
+        io_apics.write(&route, register, update(low, REDIRECTION_MASKED));
+    }
+}
+
+/// Signal the end of interrupt to the local APIC.
+pub(super) fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+/// Handler for spurious local APIC interrupts, which must not be
+/// acknowledged.
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    stats::record(SPURIOUS_VECTOR);
+}
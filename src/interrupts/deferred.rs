@@ -0,0 +1,123 @@
+//! Deferred work for interrupt handlers.
+//!
+//! Interrupt handlers run with interrupts disabled, and anything they lock
+//! may already be held by the code they interrupted, such as the VGA writer
+//! during a `println!`. Instead of doing that kind of work themselves,
+//! handlers queue a small work item here, and the `worker` task runs it
+//! later with interrupts enabled.
+//!
+//! The queue is drained by a task rather than on interrupt exit, because
+//! work run on interrupt exit would still be running on top of the
+//! interrupted code and could deadlock on the same locks.
+
+use conquer_once::spin::OnceCell;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+
+/// Number of work items that can be waiting at once.
+pub const QUEUE_CAPACITY: usize = 256;
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Work items dropped because the queue was full or not yet created.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// A function to call later with interrupts enabled, and its argument.
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Work { func, arg }
+    }
+
+    fn run(self) {
+        (self.func)(self.arg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// `init` has not been called yet.
+    Uninitialised,
+    /// `QUEUE_CAPACITY` items are already waiting.
+    QueueFull,
+}
+
+/// Create the queue. It is allocated on the heap, so this must be called
+/// after the heap has been initialised.
+pub fn init() {
+    QUEUE
+        .try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("deferred::init should only be called once");
+}
+
+/// Queue `work` to be run by the worker task. Safe to call from interrupt
+/// handlers, as it neither locks nor allocates.
+///
+/// Work that cannot be queued is dropped and counted, and the worker reports
+/// the count once it runs.
+pub fn defer(work: Work) -> Result<(), DeferError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue.push(work).map_err(|_| DeferError::QueueFull),
+        Err(_) => Err(DeferError::Uninitialised),
+    };
+
+    match result {
+        Ok(()) => WAKER.wake(),
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+/// Returns the number of work items dropped since boot.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run every queued work item. Returns how many were run.
+pub fn run_pending() -> usize {
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+
+    let mut count = 0;
+    while let Some(work) = queue.pop() {
+        work.run();
+        count += 1;
+    }
+    count
+}
+
+/// The task that runs deferred work. Spawn it on the executor after `init`.
+pub async fn worker() {
+    let mut reported = 0;
+
+    poll_fn(|cx| {
+        // Register first, so work queued while draining still wakes us.
+        WAKER.register(cx.waker());
+        run_pending();
+
+        let dropped = dropped();
+        if dropped != reported {
+            crate::println!(
+                "WARNING: {} deferred work items dropped",
+                dropped - reported
+            );
+            reported = dropped;
+        }
+
+        Poll::<()>::Pending
+    })
+    .await
+}
@@ -0,0 +1,297 @@
+//! Dynamic registration of hardware interrupt handlers.
+//!
+//! Every legacy IRQ line has a dispatch stub in the IDT. The stub calls each
+//! handler registered for its line and then signals the end of interrupt, so
+//! handlers never deal with the interrupt controller themselves. Lines
+//! without any handler are kept masked, at the PICs or at the I/O APIC once
+//! `switch_to_apic` has been called.
+//!
+//! Up to `MAX_SHARED_HANDLERS` handlers can share a line. All of them are
+//! called on every interrupt, so each one must check whether its device is
+//! the one that raised it.
+
+use super::{apic, stats, PICS, PIC_1_OFFSET};
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+/// Number of IRQ lines on the chained PICs.
+pub const IRQ_LINES: u8 = 16;
+
+/// Number of handlers that can share a single IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// The primary PIC line the secondary PIC is chained to. It never raises an
+/// interrupt itself.
+const CASCADE_IRQ: u8 = 2;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+
+/// OCW3 command selecting the in-service register for the next read of the
+/// command port.
+const READ_ISR: u8 = 0x0b;
+
+/// A hardware interrupt handler, called with the IRQ line that fired.
+///
+/// Handlers run in interrupt context with interrupts disabled, so they must
+/// be short and must not take locks that are held with interrupts enabled.
+pub type IrqHandler = fn(irq: u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line does not exist or is the cascade line.
+    InvalidLine,
+    /// The line already has `MAX_SHARED_HANDLERS` handlers.
+    LineFull,
+    /// The handler is already registered for the line.
+    AlreadyRegistered,
+    /// The handler is not registered for the line.
+    NotRegistered,
+}
+
+type HandlerList = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+/// Registered handlers, indexed by IRQ line.
+static HANDLERS: IrqSafeMutex<[HandlerList; IRQ_LINES as usize]> =
+    IrqSafeMutex::new("irq::HANDLERS", [[None; MAX_SHARED_HANDLERS]; IRQ_LINES as usize]);
+
+/// Number of IRQ handlers running, counting nested ones.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the current code is running in an IRQ handler.
+pub fn in_interrupt() -> bool {
+    DEPTH.load(Ordering::Relaxed) != 0
+}
+
+fn check_line(irq: u8) -> Result<(), IrqError> {
+    if irq >= IRQ_LINES || irq == CASCADE_IRQ {
+        Err(IrqError::InvalidLine)
+    } else {
+        Ok(())
+    }
+}
+
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool {
+    a as usize == b as usize
+}
+
+/// Register `handler` for the IRQ line `irq`, unmasking the line if it was
+/// masked.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_line(irq)?;
+
+    let mut handlers = HANDLERS.lock();
+    let line = &mut handlers[usize::from(irq)];
+
+    if line.iter().flatten().any(|&h| same_handler(h, handler)) {
+        return Err(IrqError::AlreadyRegistered);
+    }
+    let slot = line
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(IrqError::LineFull)?;
+    *slot = Some(handler);
+
+    set_masked(irq, false);
+    Ok(())
+}
+
+/// Remove `handler` from the IRQ line `irq`, masking the line if no handlers
+/// are left.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_line(irq)?;
+
+    let mut handlers = HANDLERS.lock();
+    let line = &mut handlers[usize::from(irq)];
+
+    let slot = line
+        .iter_mut()
+        .find(|slot| matches!(slot, Some(h) if same_handler(*h, handler)))
+        .ok_or(IrqError::NotRegistered)?;
+    *slot = None;
+
+    if line.iter().all(|slot| slot.is_none()) {
+        set_masked(irq, true);
+    }
+    Ok(())
+}
+
+/// Mask every line without a handler and unmask the rest. Called once the
+/// PICs have been initialised.
+pub(super) fn apply_masks() {
+    let handlers = HANDLERS.lock();
+    let _pics = PICS.lock();
+
+    let mut masks = [0xffu8; 2];
+    for (irq, line) in handlers.iter().enumerate() {
+        if line.iter().any(|slot| slot.is_some()) {
+            masks[irq / 8] &= !(1 << (irq % 8));
+        }
+    }
+    // The secondary PIC can only deliver through the cascade line.
+    if masks[1] != 0xff {
+        masks[0] &= !(1 << CASCADE_IRQ);
+    }
+
+    unsafe {
+        Port::new(PIC_1_DATA).write(masks[0]);
+        Port::new(PIC_2_DATA).write(masks[1]);
+    }
+}
+
+/// Move interrupt delivery from the PICs to the APICs, keeping the lines
+/// that have handlers unmasked. Returns false if there is no usable APIC, in
+/// which case the PICs stay in use.
+pub(super) fn switch_to_apic() -> bool {
+    let handlers = HANDLERS.lock();
+
+    if !apic::init() {
+        return false;
+    }
+
+    {
+        let _pics = PICS.lock();
+        unsafe {
+            Port::<u8>::new(PIC_1_DATA).write(0xff);
+            Port::<u8>::new(PIC_2_DATA).write(0xff);
+        }
+    }
+
+    for (irq, line) in handlers.iter().enumerate() {
+        if line.iter().any(|slot| slot.is_some()) {
+            apic::set_masked(irq as u8, false);
+        }
+    }
+    true
+}
+
+/// Set or clear the mask bit for a line in the interrupt controller that
+/// owns it.
+///
+/// Must be called with `HANDLERS` held.
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        return apic::set_masked(irq, masked);
+    }
+
+    let _pics = PICS.lock();
+
+    let (port, bit) = if irq < 8 {
+        (PIC_1_DATA, irq)
+    } else {
+        (PIC_2_DATA, irq - 8)
+    };
+    let mut port: Port<u8> = Port::new(port);
+
+    unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | 1 << bit);
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+
+        // The secondary PIC can only deliver through the cascade line.
+        if !masked && irq >= 8 {
+            let mut primary: Port<u8> = Port::new(PIC_1_DATA);
+            let mask = primary.read();
+            primary.write(mask & !(1 << CASCADE_IRQ));
+        }
+    }
+}
+
+/// Returns whether the interrupt on `irq` is in service at its PIC.
+///
+/// A PIC raises IRQ7 or IRQ15 when a request goes away before the CPU
+/// acknowledges it. Such a spurious interrupt is not in service.
+fn in_service(irq: u8) -> bool {
+    let (command, bit) = if irq < 8 {
+        (PIC_1_COMMAND, irq)
+    } else {
+        (PIC_2_COMMAND, irq - 8)
+    };
+    let mut port: Port<u8> = Port::new(command);
+
+    let isr = unsafe {
+        port.write(READ_ISR);
+        port.read()
+    };
+    isr & (1 << bit) != 0
+}
+
+/// Call every handler registered for `irq`, then signal the end of interrupt.
+fn dispatch(irq: u8) {
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    handle(irq);
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn handle(irq: u8) {
+    let apic = apic::is_enabled();
+
+    if !apic && (irq == 7 || irq == 15) && !in_service(irq) {
+        stats::record_spurious(irq);
+
+        // The secondary PIC must not see an end of interrupt for a spurious
+        // IRQ15, but the primary PIC did deliver it through the cascade.
+        if irq == 15 {
+            unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ);
+            }
+        }
+        return;
+    }
+    stats::record(PIC_1_OFFSET + irq);
+
+    // Copy the handlers out so they are free to register and unregister.
+    let handlers = HANDLERS.lock()[usize::from(irq)];
+
+    for handler in handlers.iter().flatten() {
+        handler(irq);
+    }
+
+    if apic {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+        }
+    }
+}
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// The dispatch stubs, indexed by IRQ line.
+        pub(super) const STUBS: [HandlerFunc; IRQ_LINES as usize] = [$($stub),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq0_stub,
+    1 => irq1_stub,
+    2 => irq2_stub,
+    3 => irq3_stub,
+    4 => irq4_stub,
+    5 => irq5_stub,
+    6 => irq6_stub,
+    7 => irq7_stub,
+    8 => irq8_stub,
+    9 => irq9_stub,
+    10 => irq10_stub,
+    11 => irq11_stub,
+    12 => irq12_stub,
+    13 => irq13_stub,
+    14 => irq14_stub,
+    15 => irq15_stub,
+}
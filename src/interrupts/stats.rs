@@ -0,0 +1,108 @@
+//! Interrupt statistics.
+//!
+//! Every handler counts how often its vector fired, and the IRQ dispatcher
+//! separately counts spurious interrupts from the PICs. The counters can be
+//! read individually or printed as a table in the style of
+//! `/proc/interrupts`.
+
+use super::irq::IRQ_LINES;
+use super::PIC_1_OFFSET;
+use crate::crash;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const VECTORS: usize = 256;
+
+/// Number of times each vector fired, indexed by vector.
+static COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+
+/// Number of spurious interrupts on each IRQ line. Only IRQ7 and IRQ15 can
+/// be spurious.
+static SPURIOUS: [AtomicU64; IRQ_LINES as usize] =
+    [const { AtomicU64::new(0) }; IRQ_LINES as usize];
+
+/// What is wired to each legacy IRQ line on a PC.
+const IRQ_NAMES: [&str; IRQ_LINES as usize] = [
+    "timer",
+    "keyboard",
+    "cascade",
+    "serial port 2",
+    "serial port 1",
+    "parallel port 2/3",
+    "floppy disk",
+    "parallel port 1",
+    "real time clock",
+    "acpi",
+    "available",
+    "available",
+    "mouse",
+    "co-processor",
+    "primary ata",
+    "secondary ata",
+];
+
+/// Count an interrupt on `vector`.
+pub(crate) fn record(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a spurious interrupt on the IRQ line `irq`.
+pub(super) fn record_spurious(irq: u8) {
+    SPURIOUS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many times `vector` has fired since boot.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Returns how many spurious interrupts the IRQ line `irq` has raised since
+/// boot. These are not included in the count for its vector.
+pub fn spurious(irq: u8) -> u64 {
+    SPURIOUS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Returns a table of every vector that has fired, with its count.
+pub fn report() -> Report {
+    Report
+}
+
+/// A snapshot of the counters, printed one vector per line.
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>5} {:>12}  NAME", "VEC", "COUNT")?;
+
+        for vector in 0..=u8::MAX {
+            let count = count(vector);
+            if count == 0 {
+                continue;
+            }
+            write!(f, "{:>5} {:>12}  ", vector, count)?;
+
+            let irq = vector.wrapping_sub(PIC_1_OFFSET);
+            if let Some(exception) = crash::exception(vector) {
+                writeln!(f, "{} {}", exception.mnemonic, exception.name)?;
+            } else if irq < IRQ_LINES {
+                writeln!(f, "IRQ{} {}", irq, IRQ_NAMES[usize::from(irq)])?;
+            } else {
+                writeln!(f, "-")?;
+            }
+        }
+
+        write!(f, "{:>5} {:>12}  spurious IRQ7", "SPU", spurious(7))?;
+        write!(f, "\n{:>5} {:>12}  spurious IRQ15", "SPU", spurious(15))
+    }
+}
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = count(crash::BREAKPOINT.vector);
+
+    x86_64::instructions::interrupts::int3();
+
+    assert_eq!(count(crash::BREAKPOINT.vector), before + 1);
+}
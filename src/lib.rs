@@ -14,11 +14,18 @@ extern crate alloc;
 
 pub mod VGA_BUFFER;
 pub mod gdt;
+pub mod acpi;
 pub mod allocator;
+pub mod cmdline;
+pub mod crash;
+pub mod debug;
 pub mod interrupts;
 pub mod memory;
+pub mod rtc;
 pub mod serial;
+pub mod sync;
 pub mod task;
+pub mod time;
 
 /// Initialises the kernel, to be called at the entry point of main
 pub fn init_kernel() {
@@ -28,7 +35,9 @@ pub fn init_kernel() {
 
     interrupts::init_idt();
 
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_pics();
+
+    rtc::init();
 
     x86_64::instructions::interrupts::enable(); // set sti
     println!("Kernel initiased successfully.");
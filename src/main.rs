@@ -12,10 +12,16 @@ use bootloader::{entry_point, BootInfo};
 use kernel_dev::task::keyboard;
 use core::panic::PanicInfo;
 use kernel_dev::allocator;
-use kernel_dev::memory::{self, BootInfoFrameAllocator};
+use kernel_dev::debug;
+use kernel_dev::interrupts;
+use kernel_dev::memory::{self, BuddyFrameAllocator};
 use kernel_dev::task::{simple_executor::SimpleExecutor, Task};
+use kernel_dev::time;
 use x86_64::{structures::paging::Page, VirtAddr};
 
+// The local copies of these modules lock through `crate::sync`.
+use kernel_dev::sync;
+
 mod VGA_BUFFER;
 mod serial;
 
@@ -25,17 +31,61 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     kernel_dev::init_kernel();
 
+    // Breakpoints drop into the monitor on COM1.
+    debug::set_backend(debug::Backend::Monitor);
+
     x86_64::instructions::interrupts::enable(); // set sti
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    // Before anything is mapped with `memory::protect::no_execute`.
+    memory::protect::enable_nx();
+
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    println!(
+        "Frames: {} free of {}",
+        frame_allocator.free_frames(),
+        frame_allocator.total_frames()
+    );
 
     // new
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let heap_size = allocator::boot_heap_size(&boot_info.memory_map);
+    allocator::init_heap_sized(&mut mapper, &mut frame_allocator, heap_size)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    // Device registers are mapped with `memory::map_mmio` from here on.
+    print!("Initialising APIC...");
+    if interrupts::init_apic() {
+        println!("[ok]");
+    } else {
+        println!("[not found, using PIC]");
+    }
+
+    print!("Initialising clock...");
+    match time::init() {
+        Ok(source) => println!("[ok] {} at {} Hz", source, time::frequency().unwrap_or(0)),
+        Err(err) => println!("[failed] {:?}", err),
+    }
+
+    print!("Protecting kernel memory...");
+    match memory::protect::protect_kernel() {
+        Ok(protection) => println!("[ok] {}", protection),
+        Err(err) => println!("[failed] {:?}", err),
+    }
+    interrupts::deferred::init();
+
+    print!("Starting watchdog...");
+    match debug::watchdog::start(debug::watchdog::DEFAULT_TIMEOUT) {
+        Ok(mode) => println!("[ok] {}", mode),
+        Err(err) => println!("[failed] {:?}", err),
+    }
 
     let mut executor = SimpleExecutor::new();
     executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(interrupts::deferred::worker()));
     executor.run();
 
     #[cfg(test)]
@@ -57,6 +107,8 @@ async fn example_task(){
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Halting after a panic is not a lockup.
+    debug::watchdog::stop();
     println!("{}", info);
 
     kernel_dev::hlt_loop();
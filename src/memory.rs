@@ -1,16 +1,85 @@
+use crate::sync::Mutex;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        page_table::PageTableEntry, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+pub mod bitmap;
+pub mod buddy;
+pub mod dump;
+pub mod mmio;
+pub mod protect;
+pub mod vmm;
+pub mod walk;
+
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::BuddyFrameAllocator;
+pub use mmio::{map_mmio, MmioRegion};
+pub use walk::{CacheType, EffectiveFlags, Mapping};
+
+/// Virtual address at which the bootloader mapped the physical memory, set
+/// by `init`.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// Record the offset of the physical memory mapping and return a mapper for
+/// the active page tables.
+///
+/// Panics if called again with a different offset.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset`, and that
+/// no two mappers returned by it are in use at the same time, or the level 4
+/// table would be aliased mutably.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let offset = PHYSICAL_MEMORY_OFFSET.get_or_init(|| physical_memory_offset);
+    assert_eq!(
+        *offset, physical_memory_offset,
+        "physical memory offset changed"
+    );
+
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the offset of the physical memory mapping, or `None` if `init`
+/// has not been called yet.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.try_get().ok().copied()
+}
+
+/// The kernel's page tables and frame allocator, for mappings made after
+/// boot.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frames: BuddyFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new("memory::KERNEL_MEMORY", None);
+
+/// Hand the page tables and frame allocator over to the kernel once the
+/// heap has been set up with them, so that later mappings can be made.
+pub fn install(mapper: OffsetPageTable<'static>, frames: BuddyFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frames });
+}
+
+/// Run `f` with the kernel's page tables and frame allocator. Returns `None`
+/// if `install` has not been called yet.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.lock().as_mut().map(f)
+}
+
+/// Like `with_kernel_memory`, but also returns `None` rather than waiting if
+/// the page tables are in use. The page fault handler may have interrupted
+/// their holder.
+pub(crate) fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+}
+
 pub struct EmptyFrameAllocator;
 
 // Unsafe as the FrameAllocator must only return empty frames, if it does not
@@ -99,8 +168,25 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr //unsafe
 }
 
+/// Walk the active page tables to find how `addr` is mapped, with the
+/// permissions and caching that apply to it. Returns `None` if the address
+/// is not mapped or `init` has not been called yet.
+pub fn walk(addr: VirtAddr) -> Option<Mapping> {
+    walk::walk_with(addr, physical_memory_offset()?, |_, _, _| ())
+}
+
+/// Like `walk`, but also calls `visit` with the level, index and contents of
+/// each page table entry on the way, down to the leaf or the first entry
+/// that is not present.
+pub fn walk_entries(
+    addr: VirtAddr,
+    visit: impl FnMut(u8, u16, &PageTableEntry),
+) -> Option<Mapping> {
+    walk::walk_with(addr, physical_memory_offset()?, visit)
+}
+
 /// Translates the given virtual address to the mapped physical address, or
-/// `None` if the address is not mapped.
+/// `None` if the address is not mapped. Huge pages are followed too.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
@@ -115,35 +201,5 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
 /// the whole body of unsafe functions as an unsafe block. This function must
 /// only be reachable through `unsafe fn` from outside of this module.
 fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::page_table::FrameError;
-
-    //read the active level 4 frame from the register
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let table_indexes = [
-        addr.p4_index(),
-        addr.p3_index(),
-        addr.p2_index(),
-        addr.p1_index(),
-    ];
-    let mut frame = level_4_table_frame;
-
-    for &index in &table_indexes {
-        //convert the frame to a page table ref
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe { &*table_ptr };
-
-        //read the table entry and update frame
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("Huge pages not supported."),
-        };
-    }
-
-    //calculate physical address by adding page ofset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    walk::walk_with(addr, physical_memory_offset, |_, _, _| ()).map(|mapping| mapping.phys_addr)
 }
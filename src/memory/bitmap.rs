@@ -0,0 +1,175 @@
+//! A physical frame allocator keeping one bit per frame.
+//!
+//! The bitmap covers every frame from physical address 0 up to the end of
+//! the last usable region. It has to exist before the heap does, so it is
+//! stored in the first usable region large enough to hold it and reached
+//! through the physical memory mapping.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Frames per bitmap word.
+const BITS: usize = 64;
+
+/// Frame allocator over the usable regions of the boot memory map, which can
+/// also free frames.
+pub struct BitmapFrameAllocator {
+    /// One bit per frame, set while the frame is free.
+    bitmap: &'static mut [u64],
+    /// The boot memory map, to tell which frames may ever be freed.
+    memory_map: &'static MemoryMap,
+    /// The frames holding the bitmap.
+    bitmap_frames: Range<usize>,
+    /// The word the next search starts at. Every word before it is zero.
+    next: usize,
+    total: usize,
+    free: usize,
+    reserved: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a frame allocator from the usable regions of `memory_map`.
+    ///
+    /// Panics if no usable region is large enough to hold the bitmap.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory map is valid, that no usable frame is in use, and that the
+    /// complete physical memory is mapped at `physical_memory_offset`. It
+    /// must only be called once, or frames would be handed out twice.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let end = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let total = (end / FRAME_SIZE) as usize;
+        let words = total.div_ceil(BITS);
+        let bitmap_size = (words * 8) as u64;
+
+        let bitmap_start = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region can hold the frame bitmap");
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(0);
+
+        let first = (bitmap_start / FRAME_SIZE) as usize;
+        let count = bitmap_size.div_ceil(FRAME_SIZE) as usize;
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            memory_map,
+            bitmap_frames: first..first + count,
+            next: 0,
+            total,
+            free: 0,
+            reserved: 0,
+        };
+        for region in usable() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.set_free(frame as usize, true);
+            }
+        }
+
+        for frame in first..first + count {
+            allocator.set_free(frame, false);
+        }
+
+        allocator.free = allocator
+            .bitmap
+            .iter()
+            .map(|w| w.count_ones() as usize)
+            .sum();
+        allocator.reserved = total - allocator.free;
+        allocator
+    }
+
+    /// Number of frames covered by the bitmap.
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Number of frames that can be allocated right now.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of frames that are never handed out: those that are not usable
+    /// in the memory map, and those holding the bitmap.
+    pub fn reserved_frames(&self) -> usize {
+        self.reserved
+    }
+
+    /// Number of frames handed out and not yet freed.
+    pub fn allocated_frames(&self) -> usize {
+        self.total - self.reserved - self.free
+    }
+
+    /// Returns whether `frame` is ever handed out, that is whether it is
+    /// usable and does not hold the bitmap.
+    fn is_allocatable(&self, frame: usize) -> bool {
+        let usable = self.memory_map.iter().any(|r| {
+            r.region_type == MemoryRegionType::Usable
+                && (r.range.start_frame_number..r.range.end_frame_number).contains(&(frame as u64))
+        });
+        usable && !self.bitmap_frames.contains(&frame)
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, free: bool) {
+        let word = &mut self.bitmap[frame / BITS];
+        if free {
+            *word |= 1 << (frame % BITS);
+        } else {
+            *word &= !(1 << (frame % BITS));
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let index = match (self.next..self.bitmap.len()).find(|&i| self.bitmap[i] != 0) {
+            Some(index) => index,
+            None => {
+                self.next = self.bitmap.len();
+                return None;
+            }
+        };
+        self.next = index;
+
+        let frame = index * BITS + self.bitmap[index].trailing_zeros() as usize;
+        self.set_free(frame, false);
+        self.free -= 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame as u64 * FRAME_SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Panics if the frame is free already or was never handed out.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            self.is_allocatable(index) && !self.is_free(index),
+            "{:?} is not allocated",
+            frame
+        );
+
+        self.set_free(index, true);
+        self.free += 1;
+        self.next = self.next.min(index / BITS);
+    }
+}
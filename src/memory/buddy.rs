@@ -0,0 +1,287 @@
+//! A buddy allocator for physically contiguous runs of frames.
+//!
+//! Memory is handed out in naturally aligned blocks of `2^order` frames,
+//! from a single 4KiB frame at order 0 up to 1GiB at `MAX_ORDER`. A larger
+//! block is split in halves, its buddies, to satisfy a smaller request, and
+//! a freed block is merged with its buddy again whenever both are free.
+//!
+//! The free lists are linked through the free blocks themselves. Besides
+//! them, one byte per frame records which frames start a free block and its
+//! order, so that finding out whether a buddy is free takes constant time.
+//! That table is stored in the first usable region large enough to hold it.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// The largest order, a 1GiB block.
+pub const MAX_ORDER: usize = 18;
+
+/// Marks the end of a free list.
+const NONE: u64 = u64::MAX;
+
+/// Links of a free list, stored at the start of each free block.
+#[repr(C)]
+struct Links {
+    next: u64,
+    prev: u64,
+}
+
+/// Frame allocator handing out physically contiguous, naturally aligned
+/// blocks of frames.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    /// Physical address of the first free block of each order.
+    free_lists: [u64; MAX_ORDER + 1],
+    /// For every frame, its order plus one if it starts a free block, or 0.
+    heads: &'static mut [u8],
+    total: usize,
+    free: usize,
+}
+
+/// Returns the number of bytes in a block of `order`.
+const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+impl BuddyFrameAllocator {
+    /// Create a buddy allocator from the usable regions of `memory_map`.
+    ///
+    /// Panics if no usable region is large enough to hold the table of free
+    /// blocks.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory map is valid, that no usable frame is in use, and that the
+    /// complete physical memory is mapped at `physical_memory_offset`. It
+    /// must only be called once, or frames would be handed out twice.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_addr()..r.range.end_addr())
+        };
+
+        let end = usable().map(|r| r.end).max().unwrap_or(0);
+        let frames = (end / FRAME_SIZE) as usize;
+        let table_size = (frames as u64 + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+
+        let table_start = usable()
+            .find(|r| r.end - r.start >= table_size)
+            .map(|r| r.start)
+            .expect("no usable region can hold the buddy allocator's table");
+        let table_ptr = (physical_memory_offset + table_start).as_mut_ptr::<u8>();
+
+        let heads = core::slice::from_raw_parts_mut(table_ptr, frames);
+        heads.fill(0);
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [NONE; MAX_ORDER + 1],
+            heads,
+            total: frames,
+            free: 0,
+        };
+        for region in usable() {
+            let start = if region.start == table_start {
+                region.start + table_size
+            } else {
+                region.start
+            };
+            allocator.add_range(start, region.end);
+        }
+        allocator
+    }
+
+    /// Add `start..end` to the free lists in the largest aligned blocks that
+    /// fit.
+    fn add_range(&mut self, mut start: u64, end: u64) {
+        while start + FRAME_SIZE <= end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| {
+                    start.is_multiple_of(block_size(order)) && start + block_size(order) <= end
+                })
+                .unwrap_or(0);
+
+            self.push(start, order);
+            self.free += 1 << order;
+            start += block_size(order);
+        }
+    }
+
+    /// Number of frames covered by the allocator, including the ones that
+    /// are not usable.
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Number of frames that can be allocated right now.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of free blocks of `order`.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_lists[order];
+        while block != NONE {
+            count += 1;
+            block = self.next_free(block);
+        }
+        count
+    }
+
+    /// Allocate a block of `2^order` frames, aligned to its size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut found = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+
+        let block = self.free_lists[found];
+        self.remove(block, found);
+
+        // Give back the upper halves until the block is the requested size.
+        while found > order {
+            found -= 1;
+            self.push(block + block_size(found), found);
+        }
+
+        self.free -= 1 << order;
+        Some(PhysAddr::new(block))
+    }
+
+    /// Free a block returned by `allocate`, merging it with its buddies.
+    ///
+    /// Panics if `addr` is not aligned to the order or is already free.
+    ///
+    /// This function is unsafe because the block must have been allocated
+    /// with the same order and must not be in use any more.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let mut block = addr.as_u64();
+        assert!(
+            order <= MAX_ORDER && block.is_multiple_of(block_size(order)),
+            "{:?} is not a block of order {}",
+            addr,
+            order
+        );
+        assert!(
+            self.index(block) < self.heads.len(),
+            "{:?} is outside the allocator",
+            addr
+        );
+        assert!(
+            !self.is_within_free_block(block),
+            "{:?} is already free",
+            addr
+        );
+        self.free += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = block ^ block_size(order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    fn index(&self, addr: u64) -> usize {
+        (addr / FRAME_SIZE) as usize
+    }
+
+    fn is_free_block(&self, addr: u64, order: usize) -> bool {
+        self.heads.get(self.index(addr)) == Some(&(order as u8 + 1))
+    }
+
+    /// Returns whether `addr` lies in a free block of any order. A freed
+    /// frame may have been merged into a larger block, so every aligned
+    /// block enclosing it is checked, not only the one it starts.
+    fn is_within_free_block(&self, addr: u64) -> bool {
+        (0..=MAX_ORDER).any(|order| {
+            let block = addr & !(block_size(order) - 1);
+            self.heads
+                .get(self.index(block))
+                .is_some_and(|&head| usize::from(head) > order)
+        })
+    }
+
+    fn links(&mut self, addr: u64) -> &mut Links {
+        unsafe { &mut *(self.physical_memory_offset + addr).as_mut_ptr::<Links>() }
+    }
+
+    fn next_free(&self, addr: u64) -> u64 {
+        unsafe { (*(self.physical_memory_offset + addr).as_ptr::<Links>()).next }
+    }
+
+    fn push(&mut self, addr: u64, order: usize) {
+        let head = self.free_lists[order];
+        *self.links(addr) = Links {
+            next: head,
+            prev: NONE,
+        };
+        if head != NONE {
+            self.links(head).prev = addr;
+        }
+        self.free_lists[order] = addr;
+
+        let index = self.index(addr);
+        self.heads[index] = order as u8 + 1;
+    }
+
+    fn remove(&mut self, addr: u64, order: usize) {
+        let Links { next, prev } = *self.links(addr);
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            self.links(prev).next = next;
+        }
+        if next != NONE {
+            self.links(next).prev = prev;
+        }
+
+        let index = self.index(addr);
+        self.heads[index] = 0;
+    }
+}
+
+/// Returns the order of a block the size of a `S` page.
+fn page_order<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(page_order::<Size4KiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(page_order::<Size2MiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate(page_order::<Size1GiB>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        self.deallocate(frame.start_address(), page_order::<S>());
+    }
+}
@@ -0,0 +1,165 @@
+//! A listing of the active address space.
+//!
+//! Every present page is visited in address order, and runs of pages that
+//! map contiguous physical memory with the same page size and flags are
+//! merged into a single region, so even the bootloader's mapping of all
+//! physical memory fits on a few lines.
+
+use super::walk::{self, Access, EffectiveFlags};
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A run of pages mapped to contiguous physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// The size of the region, in bytes.
+    pub size: u64,
+    pub phys_start: PhysAddr,
+    pub page_size: u64,
+    pub flags: EffectiveFlags,
+}
+
+impl Region {
+    /// Returns whether `next` carries on where this region ends.
+    fn is_continued_by(&self, next: &Region) -> bool {
+        self.start.as_u64().checked_add(self.size) == Some(next.start.as_u64())
+            && self.phys_start + self.size == next.phys_start
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = &self.flags;
+        let page_size = match self.page_size {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G",
+        };
+
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {} r{}{} {} {} {}",
+            self.start.as_u64(),
+            self.start.as_u64() + (self.size - 1),
+            self.phys_start.as_u64(),
+            page_size,
+            if flags.writable { 'w' } else { '-' },
+            if flags.no_execute { '-' } else { 'x' },
+            if flags.user { "user" } else { "kern" },
+            if flags.global { 'g' } else { '-' },
+            flags.cache
+        )
+    }
+}
+
+/// Call `visit` with every region of the active address space, in address
+/// order. Nothing is visited if `memory::init` has not been called yet.
+pub fn for_each_region(mut visit: impl FnMut(&Region)) {
+    let physical_memory_offset = match super::physical_memory_offset() {
+        Some(offset) => offset,
+        None => return,
+    };
+    let pat = walk::read_pat();
+    let mut current: Option<Region> = None;
+
+    let mut emit = |next: Region| {
+        match current.as_mut() {
+            Some(region) if region.is_continued_by(&next) => {
+                region.size += next.size;
+                return;
+            }
+            _ => {}
+        }
+        if let Some(region) = current.replace(next) {
+            visit(&region);
+        }
+    };
+
+    let level_4_table = Cr3::read().0.start_address();
+    visit_table(
+        physical_memory_offset,
+        pat,
+        level_4_table,
+        4,
+        0,
+        Access::TOP,
+        &mut emit,
+    );
+
+    if let Some(region) = current {
+        visit(&region);
+    }
+}
+
+/// Emit a one page region for every leaf entry below the table at
+/// `table_addr`, whose first entry maps the virtual address `base`.
+fn visit_table(
+    physical_memory_offset: VirtAddr,
+    pat: u64,
+    table_addr: PhysAddr,
+    level: u8,
+    base: u64,
+    access: Access,
+    emit: &mut dyn FnMut(Region),
+) {
+    let virt = physical_memory_offset + table_addr.as_u64();
+    let table = unsafe { &*virt.as_ptr::<PageTable>() };
+    let entry_size = walk::entry_size(level);
+
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * entry_size;
+
+        if walk::is_leaf(level, entry) {
+            emit(Region {
+                // Addresses in the upper half of the level 4 table are sign
+                // extended.
+                start: VirtAddr::new_truncate(start),
+                size: entry_size,
+                phys_start: walk::leaf_frame(level, entry),
+                page_size: entry_size,
+                flags: walk::leaf_flags(level, entry, access, pat),
+            });
+        } else {
+            visit_table(
+                physical_memory_offset,
+                pat,
+                entry.addr(),
+                level - 1,
+                start,
+                access.through(entry.flags()),
+                emit,
+            );
+        }
+    }
+}
+
+/// The regions of the active address space, printed one per line.
+pub struct AddressSpace(());
+
+/// Returns the active address space, to be printed.
+pub fn address_space() -> AddressSpace {
+    AddressSpace(())
+}
+
+impl fmt::Display for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = writeln!(
+            f,
+            "VIRTUAL                                  PHYSICAL       PG ACC MODE G CACHE"
+        );
+        for_each_region(|region| {
+            if result.is_ok() {
+                result = writeln!(f, "{}", region);
+            }
+        });
+        result
+    }
+}
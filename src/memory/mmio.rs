@@ -0,0 +1,244 @@
+//! Mapping device registers.
+//!
+//! `map_mmio` maps a range of physical addresses into the MMIO window of the
+//! kernel address space with caching turned off, and returns a handle that
+//! reads and writes the registers with volatile accesses. Dropping the
+//! handle unmaps the range again.
+//!
+//! Write combining is provided through entry 4 of the page attribute table,
+//! which is reprogrammed from write back the first time it is asked for.
+
+use super::protect::no_execute;
+use super::vmm::{self, AreaKind, VmError};
+use super::walk::{self, IA32_PAT};
+use core::arch::x86_64::__cpuid;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The PAT entry used for write combining.
+const PAT_WC_ENTRY: u64 = 4;
+const PAT_WC: u64 = 0x01;
+
+/// In a 4KiB entry, the huge page bit selects the upper half of the PAT.
+const PTE_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static WC_READY: AtomicBool = AtomicBool::new(false);
+
+/// How accesses to a mapped device range are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioCache {
+    /// Every access goes straight to the device, in order. Right for
+    /// registers.
+    Uncacheable,
+    /// Reads may be cached, writes go through to the device.
+    WriteThrough,
+    /// Writes may be buffered and merged, reads are not cached. Meant for
+    /// frame buffers. Falls back to uncacheable without PAT support.
+    WriteCombining,
+}
+
+impl MmioCache {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            MmioCache::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            MmioCache::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            MmioCache::WriteCombining if enable_write_combining() => PTE_PAT,
+            MmioCache::WriteCombining => MmioCache::Uncacheable.flags(),
+        }
+    }
+}
+
+/// Returns whether the CPU has a page attribute table, according to CPUID.
+fn has_pat() -> bool {
+    // `__cpuid` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    let features = unsafe { __cpuid(1) };
+    features.edx & (1 << 16) != 0
+}
+
+/// Point PAT entry 4 at write combining, once. Returns false if there is no
+/// PAT.
+fn enable_write_combining() -> bool {
+    if WC_READY.load(Ordering::Acquire) {
+        return true;
+    }
+    if !has_pat() {
+        return false;
+    }
+
+    let shift = PAT_WC_ENTRY * 8;
+    let pat = walk::read_pat() & !(0xff << shift) | PAT_WC << shift;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Msr::new(IA32_PAT).write(pat);
+        x86_64::instructions::tlb::flush_all();
+    });
+    WC_READY.store(true, Ordering::Release);
+    true
+}
+
+/// A value that can be read from or written to a device register.
+pub trait MmioValue: Copy + private::Sealed {}
+
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// A mapped range of device memory, unmapped when dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    phys_addr: PhysAddr,
+    size: usize,
+    /// The reserved area, which starts at the page holding `phys_addr`.
+    area: vmm::Area,
+}
+
+impl MmioRegion {
+    /// The virtual address `phys_addr` is mapped at.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    /// The size of the region in bytes, as passed to `map_mmio`.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the `T` at `offset`.
+    ///
+    /// Panics if it does not lie within the region or is misaligned.
+    fn ptr<T: MmioValue>(&self, offset: usize) -> *mut T {
+        assert!(
+            matches!(offset.checked_add(mem::size_of::<T>()), Some(end) if end <= self.size),
+            "MMIO access at {:#x} is outside a region of {:#x} bytes",
+            offset,
+            self.size
+        );
+        let addr = self.base + offset;
+        assert!(
+            addr.is_aligned(mem::align_of::<T>() as u64),
+            "misaligned MMIO access at {:#x}",
+            offset
+        );
+        addr.as_mut_ptr::<T>()
+    }
+
+    /// Read the register at `offset` bytes into the region.
+    ///
+    /// Panics if the register does not lie within the region or is
+    /// misaligned.
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Write `value` to the register at `offset` bytes into the region.
+    ///
+    /// Panics if the register does not lie within the region or is
+    /// misaligned.
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let area = self.area;
+        super::with_kernel_memory(|memory| {
+            for offset in (0..area.size).step_by(PAGE_SIZE as usize) {
+                let page: Page<Size4KiB> = Page::containing_address(area.start + offset);
+                unsafe {
+                    // The PAT bit looks like a huge page bit to `unmap`.
+                    if let Ok(flush) = memory.mapper.update_flags(page, PageTableFlags::PRESENT) {
+                        flush.ignore();
+                    }
+                }
+                // The frames belong to the device, not the frame allocator.
+                if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                    flush.flush();
+                }
+            }
+        });
+        let _ = vmm::release(AreaKind::Mmio, area.start);
+    }
+}
+
+/// Map the `size` bytes of device memory at `phys_addr`, uncached.
+///
+/// This function is unsafe because the range must be device memory: mapping
+/// RAM that is also reached cached through the physical memory mapping is
+/// undefined behaviour on x86.
+pub unsafe fn map_mmio(phys_addr: PhysAddr, size: usize) -> Result<MmioRegion, VmError> {
+    map_mmio_with(phys_addr, size, MmioCache::Uncacheable)
+}
+
+/// Map the `size` bytes of device memory at `phys_addr` with the given
+/// caching.
+///
+/// This function is unsafe for the same reasons as `map_mmio`.
+pub unsafe fn map_mmio_with(
+    phys_addr: PhysAddr,
+    size: usize,
+    cache: MmioCache,
+) -> Result<MmioRegion, VmError> {
+    if size == 0 {
+        return Err(VmError::InvalidSize);
+    }
+    let first_frame = phys_addr.align_down(PAGE_SIZE);
+    let offset = phys_addr - first_frame;
+
+    let size_with_offset = offset.checked_add(size as u64).ok_or(VmError::OutOfSpace)?;
+    let area = vmm::reserve(AreaKind::Mmio, size_with_offset)?;
+    let region = MmioRegion {
+        base: area.start + offset,
+        phys_addr,
+        size,
+        area,
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute() | cache.flags();
+    // Dropping the region on failure unmaps what was mapped.
+    super::with_kernel_memory(|memory| {
+        for offset in (0..area.size).step_by(PAGE_SIZE as usize) {
+            let page: Page<Size4KiB> = Page::containing_address(area.start + offset);
+            let frame = PhysFrame::containing_address(first_frame + offset);
+
+            // `map_to` refuses the PAT bit, so it is set afterwards.
+            match memory
+                .mapper
+                .map_to(page, frame, flags - PTE_PAT, &mut memory.frames)
+            {
+                Ok(flush) if flags.contains(PTE_PAT) => {
+                    flush.ignore();
+                    if let Ok(flush) = memory.mapper.update_flags(page, flags) {
+                        flush.flush();
+                    }
+                }
+                Ok(flush) => flush.flush(),
+                Err(_) => return Err(VmError::OutOfMemory),
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(VmError::NotInitialised))?;
+
+    Ok(region)
+}
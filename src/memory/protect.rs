@@ -0,0 +1,349 @@
+//! Write xor execute for kernel memory.
+//!
+//! `protect_kernel` turns on no-execute support and remaps the kernel image
+//! so that no page is both writable and executable: code is read-only and
+//! executable, read-only data is only readable, and data and bss are
+//! writable but not executable. The boot stack and the physical memory
+//! mapping lose execute permission too. Finally supervisor write protection
+//! is turned on, so the kernel faults on writes to read-only pages as well.
+//!
+//! The section headers of the kernel ELF are not loaded into memory, but the
+//! program headers are. The linker groups sections with the same permissions
+//! into one loadable segment, so the segment flags are used instead. The ELF
+//! header is found through the `__ehdr_start` symbol the linker defines.
+//!
+//! Memory mapped after boot uses `no_execute` for everything but code.
+
+use super::KernelMemory;
+use crate::debug::range_is_mapped;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, Page, PageTableFlags, Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// The most pages of boot stack looked for on either side of the stack
+/// pointer.
+const MAX_STACK_PAGES: u64 = 4096;
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A loadable segment of the kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: VirtAddr,
+    /// The size of the segment in memory, in bytes.
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    fn overlaps(&self, page: Page) -> bool {
+        let start = page.start_address().as_u64();
+        self.start.as_u64() < start + PAGE_SIZE && start < self.start.as_u64() + self.size
+    }
+}
+
+/// Returns the program headers of the running kernel, or an empty slice if
+/// its ELF header or program headers are not mapped. The page tables are
+/// only walked after `memory::init`, so until then it is always empty.
+fn program_headers() -> &'static [ProgramHeader] {
+    // Taking the address of an extern static is only unsafe on older
+    // toolchains.
+    #[allow(unused_unsafe)]
+    let base = unsafe { core::ptr::addr_of!(__ehdr_start) };
+    let header_size = core::mem::size_of::<ElfHeader>() as u64;
+    if !range_is_mapped(base as u64, header_size) {
+        return &[];
+    }
+    let header = unsafe { &*(base as *const ElfHeader) };
+
+    if header.ident[..4] != *b"\x7fELF"
+        || usize::from(header.phentsize) != core::mem::size_of::<ProgramHeader>()
+    {
+        return &[];
+    }
+    // The program headers follow the ELF header in the first segment.
+    let table_size = u64::from(header.phnum) * core::mem::size_of::<ProgramHeader>() as u64;
+    let table = (base as u64).checked_add(header.phoff);
+    if !matches!(table, Some(table) if range_is_mapped(table, table_size)) {
+        return &[];
+    }
+    unsafe {
+        core::slice::from_raw_parts(
+            base.add(header.phoff as usize) as *const ProgramHeader,
+            usize::from(header.phnum),
+        )
+    }
+}
+
+/// Returns the loadable segments of the kernel image.
+pub fn kernel_segments() -> impl Iterator<Item = Segment> + Clone {
+    program_headers()
+        .iter()
+        .filter(|header| header.kind == PT_LOAD && header.memsz > 0)
+        .map(|header| Segment {
+            start: VirtAddr::new(header.vaddr),
+            size: header.memsz,
+            writable: header.flags & PF_W != 0,
+            executable: header.flags & PF_X != 0,
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectError {
+    /// The CPU does not support no-execute pages.
+    NoExecuteUnsupported,
+    /// The kernel's ELF header is not mapped.
+    NoElfHeader,
+    /// A page of the kernel image is not mapped with a 4KiB page.
+    CannotRemap(VirtAddr),
+    /// `memory::install` has not been called yet.
+    NotInitialised,
+}
+
+/// The number of pages remapped by `protect_kernel`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Protection {
+    /// Read-only and executable.
+    pub text_pages: usize,
+    /// Read-only and not executable.
+    pub rodata_pages: usize,
+    /// Writable and not executable.
+    pub data_pages: usize,
+    /// Pages of the boot stack, writable and not executable.
+    pub stack_pages: usize,
+    /// Pages left writable and executable, because a segment asks for both.
+    pub rwx_pages: usize,
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "text {} rodata {} data {} stack {} rwx {} pages",
+            self.text_pages, self.rodata_pages, self.data_pages, self.stack_pages, self.rwx_pages
+        )
+    }
+}
+
+/// Returns whether the CPU supports no-execute pages, according to CPUID.
+fn has_nx() -> bool {
+    // `__cpuid` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    let features = unsafe { __cpuid(0x8000_0001) };
+    features.edx & (1 << 20) != 0
+}
+
+/// Turn on support for no-execute pages. Returns false if the CPU has none.
+///
+/// Must be called before `no_execute` is used for mappings that should get
+/// the bit.
+pub fn enable_nx() -> bool {
+    if !has_nx() {
+        return false;
+    }
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    true
+}
+
+/// Returns `NO_EXECUTE` if no-execute pages are enabled, or no flags if the
+/// bit would be reserved and fault.
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Remap the kernel image, the boot stack and the physical memory mapping
+/// with write xor execute, then turn on supervisor write protection.
+///
+/// Code that has to write to read-only memory, like the debugger placing
+/// breakpoints, must clear `CR0.WP` around the write from then on.
+pub fn protect_kernel() -> Result<Protection, ProtectError> {
+    if !enable_nx() {
+        return Err(ProtectError::NoExecuteUnsupported);
+    }
+    if kernel_segments().next().is_none() {
+        return Err(ProtectError::NoElfHeader);
+    }
+
+    let protection = super::with_kernel_memory(|memory| {
+        let mut protection = protect_image(memory)?;
+        protection.stack_pages = protect_stack(memory);
+        protect_physical_memory(memory);
+        Ok(protection)
+    })
+    .unwrap_or(Err(ProtectError::NotInitialised))?;
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    Ok(protection)
+}
+
+/// Returns the flags of the 4KiB page `page`, if that is how it is mapped.
+fn page_flags(memory: &KernelMemory, page: Page) -> Option<PageTableFlags> {
+    match memory.mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(_),
+            flags,
+            ..
+        } => Some(flags),
+        _ => None,
+    }
+}
+
+fn set_flags(memory: &mut KernelMemory, page: Page, flags: PageTableFlags) {
+    if let Ok(flush) = unsafe { memory.mapper.update_flags(page, flags) } {
+        flush.flush();
+    }
+}
+
+fn protect_image(memory: &mut KernelMemory) -> Result<Protection, ProtectError> {
+    let mut protection = Protection::default();
+
+    for segment in kernel_segments() {
+        let first = Page::<Size4KiB>::containing_address(segment.start);
+        let last = Page::containing_address(segment.start + (segment.size - 1));
+
+        for page in Page::range_inclusive(first, last) {
+            let flags =
+                page_flags(memory, page).ok_or(ProtectError::CannotRemap(page.start_address()))?;
+
+            // A page shared by two segments gets the permissions of both.
+            let (writable, executable) = kernel_segments()
+                .filter(|other| other.overlaps(page))
+                .fold((false, false), |(w, x), other| {
+                    (w || other.writable, x || other.executable)
+                });
+
+            let mut new_flags = flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE;
+            if writable {
+                new_flags |= PageTableFlags::WRITABLE;
+            }
+            if !executable {
+                new_flags |= PageTableFlags::NO_EXECUTE;
+            }
+            set_flags(memory, page, new_flags);
+
+            match (writable, executable) {
+                (false, true) => protection.text_pages += 1,
+                (false, false) => protection.rodata_pages += 1,
+                (true, false) => protection.data_pages += 1,
+                (true, true) => protection.rwx_pages += 1,
+            }
+        }
+    }
+    Ok(protection)
+}
+
+/// Take execute permission from the writable 4KiB pages around the stack
+/// pointer, which the bootloader set up as the boot stack. Returns the
+/// number of pages.
+fn protect_stack(memory: &mut KernelMemory) -> usize {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let stack_page = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+
+    let mut count = 0;
+    let mut protect = |memory: &mut KernelMemory, page: Page| -> bool {
+        match page_flags(memory, page) {
+            Some(flags) if flags.contains(PageTableFlags::WRITABLE) => {
+                set_flags(memory, page, flags | PageTableFlags::NO_EXECUTE);
+                count += 1;
+                true
+            }
+            _ => false,
+        }
+    };
+
+    for i in 0..MAX_STACK_PAGES {
+        if !protect(memory, stack_page + i) {
+            break;
+        }
+    }
+    for i in 1..MAX_STACK_PAGES {
+        if !protect(memory, stack_page - i) {
+            break;
+        }
+    }
+    count
+}
+
+/// Take execute permission from the bootloader's mapping of all physical
+/// memory, which would otherwise be a writable and executable alias of
+/// everything, the kernel image included.
+fn protect_physical_memory(memory: &mut KernelMemory) {
+    let mut addr = memory.mapper.phys_offset();
+
+    while let Some(mapping) = super::walk(addr) {
+        let flags = match memory.mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => flags | PageTableFlags::NO_EXECUTE,
+            _ => break,
+        };
+        let updated = unsafe {
+            match mapping.level {
+                1 => memory
+                    .mapper
+                    .update_flags(Page::<Size4KiB>::containing_address(addr), flags)
+                    .map(|flush| flush.flush()),
+                2 => memory
+                    .mapper
+                    .update_flags(Page::<Size2MiB>::containing_address(addr), flags)
+                    .map(|flush| flush.flush()),
+                _ => memory
+                    .mapper
+                    .update_flags(Page::<Size1GiB>::containing_address(addr), flags)
+                    .map(|flush| flush.flush()),
+            }
+        };
+        if updated.is_err() {
+            break;
+        }
+        addr += mapping.page_size();
+    }
+}
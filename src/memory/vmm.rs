@@ -0,0 +1,404 @@
+//! The layout of the kernel's virtual address space, and `vmalloc`.
+//!
+//! Mappings made after boot live in fixed windows of the lower half, one for
+//! each kind of area, well away from the bootloader's mappings of the kernel
+//! and of physical memory:
+//!
+//! | window  | start               | size    |
+//! |---------|---------------------|---------|
+//! | heap    | `0x4444_4444_0000`  | 1GiB    |
+//! | stacks  | `0x5555_0000_0000`  | 64GiB   |
+//! | MMIO    | `0x6666_0000_0000`  | 64GiB   |
+//! | vmalloc | `0x7777_0000_0000`  | 64GiB   |
+//!
+//! Each window hands out non-overlapping, page aligned ranges. Stacks and
+//! vmalloc areas are followed by an unmapped guard page, so running off the
+//! end faults instead of corrupting the next area. The heap window belongs
+//! to the heap allocator as a whole.
+//!
+//! Areas reserved with `reserve_lazy` are paged on demand: nothing is mapped
+//! up front, and the page fault handler maps a zeroed frame at the first
+//! access to each page. The exception frame of a page fault is pushed on the
+//! current stack, so a kernel stack can only be lazy once page faults run on
+//! a stack of their own.
+
+use super::protect::no_execute;
+use super::{try_with_kernel_memory, with_kernel_memory, KernelMemory};
+use crate::allocator::HEAP_START;
+use crate::sync::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Number of areas each window can hold at once.
+pub const MAX_AREAS: usize = 64;
+
+/// Size of the heap window, the most the heap can ever grow to.
+pub const HEAP_WINDOW_SIZE: u64 = 1 << 30;
+
+const STACK_START: u64 = 0x5555_0000_0000;
+const MMIO_START: u64 = 0x6666_0000_0000;
+const VMALLOC_START: u64 = 0x7777_0000_0000;
+const WINDOW_SIZE: u64 = 64 << 30;
+
+/// What a window of the kernel address space is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    Heap,
+    Stack,
+    Mmio,
+    Vmalloc,
+}
+
+const KINDS: [AreaKind; 4] = [
+    AreaKind::Heap,
+    AreaKind::Stack,
+    AreaKind::Mmio,
+    AreaKind::Vmalloc,
+];
+
+/// Number of page faults resolved by mapping a page of a lazy area.
+static LAZY_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// The size is zero.
+    InvalidSize,
+    /// No free range of the requested size is left in the window.
+    OutOfSpace,
+    /// `MAX_AREAS` areas are already reserved in the window.
+    TooManyAreas,
+    /// No area starts at the given address.
+    NotReserved,
+    /// Physical memory ran out while mapping the area.
+    OutOfMemory,
+    /// `memory::install` has not been called yet.
+    NotInitialised,
+}
+
+/// A reserved range of virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub start: VirtAddr,
+    /// The size of the area in bytes, not counting its guard page.
+    pub size: u64,
+    /// Whether pages are mapped at their first access.
+    pub lazy: bool,
+}
+
+impl Area {
+    const EMPTY: Area = Area {
+        start: VirtAddr::zero(),
+        size: 0,
+        lazy: false,
+    };
+
+    fn end(&self) -> u64 {
+        self.start.as_u64() + self.size
+    }
+}
+
+/// A window of the address space and the areas reserved in it, sorted by
+/// address.
+struct Window {
+    start: u64,
+    end: u64,
+    /// Unmapped bytes left after each area.
+    guard: u64,
+    areas: [Area; MAX_AREAS],
+    len: usize,
+}
+
+impl Window {
+    const fn new(start: u64, size: u64, guard: u64) -> Self {
+        Window {
+            start,
+            end: start + size,
+            guard,
+            areas: [Area::EMPTY; MAX_AREAS],
+            len: 0,
+        }
+    }
+
+    /// A window taken up entirely by a single area.
+    const fn reserved(start: u64, size: u64) -> Self {
+        let mut window = Window::new(start, size, 0);
+        window.areas[0] = Area {
+            start: VirtAddr::new_truncate(start),
+            size,
+            lazy: false,
+        };
+        window.len = 1;
+        window
+    }
+
+    /// Reserve the first free range that fits `size` bytes and a guard.
+    fn reserve(&mut self, size: u64, lazy: bool) -> Result<Area, VmError> {
+        if self.len == MAX_AREAS {
+            return Err(VmError::TooManyAreas);
+        }
+        let needed = size.checked_add(self.guard).ok_or(VmError::OutOfSpace)?;
+
+        let mut start = self.start;
+        let mut index = self.len;
+        for (i, area) in self.areas[..self.len].iter().enumerate() {
+            if area.start.as_u64() - start >= needed {
+                index = i;
+                break;
+            }
+            start = area.end() + self.guard;
+        }
+        if index == self.len && self.end.saturating_sub(start) < needed {
+            return Err(VmError::OutOfSpace);
+        }
+
+        let area = Area {
+            start: VirtAddr::new(start),
+            size,
+            lazy,
+        };
+        self.areas[index..=self.len].rotate_right(1);
+        self.areas[index] = area;
+        self.len += 1;
+        Ok(area)
+    }
+
+    fn position(&self, start: VirtAddr) -> Result<usize, VmError> {
+        self.areas[..self.len]
+            .iter()
+            .position(|area| area.start == start)
+            .ok_or(VmError::NotReserved)
+    }
+
+    fn release(&mut self, start: VirtAddr) -> Result<Area, VmError> {
+        let index = self.position(start)?;
+        let area = self.areas[index];
+
+        self.areas[index..self.len].rotate_left(1);
+        self.len -= 1;
+        Ok(area)
+    }
+
+    /// Returns the area that `addr` lies in, not counting guard pages.
+    fn find(&self, addr: u64) -> Option<Area> {
+        self.areas[..self.len]
+            .iter()
+            .find(|area| (area.start.as_u64()..area.end()).contains(&addr))
+            .copied()
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+struct Windows {
+    heap: Window,
+    stack: Window,
+    mmio: Window,
+    vmalloc: Window,
+}
+
+impl Windows {
+    fn get(&mut self, kind: AreaKind) -> &mut Window {
+        match kind {
+            AreaKind::Heap => &mut self.heap,
+            AreaKind::Stack => &mut self.stack,
+            AreaKind::Mmio => &mut self.mmio,
+            AreaKind::Vmalloc => &mut self.vmalloc,
+        }
+    }
+
+    fn kind_of(&mut self, addr: VirtAddr) -> Option<AreaKind> {
+        KINDS
+            .into_iter()
+            .find(|&kind| self.get(kind).contains(addr.as_u64()))
+    }
+}
+
+static WINDOWS: Mutex<Windows> = Mutex::new(
+    "vmm::WINDOWS",
+    Windows {
+        heap: Window::reserved(HEAP_START as u64, HEAP_WINDOW_SIZE),
+        stack: Window::new(STACK_START, WINDOW_SIZE, PAGE_SIZE),
+        mmio: Window::new(MMIO_START, WINDOW_SIZE, 0),
+        vmalloc: Window::new(VMALLOC_START, WINDOW_SIZE, PAGE_SIZE),
+    },
+);
+
+/// Round `size` up to whole pages, or `None` if that overflows.
+fn page_align(size: u64) -> Option<u64> {
+    Some(size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+/// Reserve a range of at least `size` bytes in the window for `kind`. The
+/// size is rounded up to whole pages, and nothing is mapped.
+pub fn reserve(kind: AreaKind, size: u64) -> Result<Area, VmError> {
+    reserve_area(kind, size, false)
+}
+
+/// Reserve a range like `reserve`, whose pages are mapped to zeroed frames
+/// when they are first accessed.
+pub fn reserve_lazy(kind: AreaKind, size: u64) -> Result<Area, VmError> {
+    reserve_area(kind, size, true)
+}
+
+fn reserve_area(kind: AreaKind, size: u64, lazy: bool) -> Result<Area, VmError> {
+    if size == 0 {
+        return Err(VmError::InvalidSize);
+    }
+    let size = page_align(size).ok_or(VmError::OutOfSpace)?;
+    WINDOWS.lock().get(kind).reserve(size, lazy)
+}
+
+/// Give back the area starting at `start`. Whatever is mapped in it stays
+/// mapped.
+pub fn release(kind: AreaKind, start: VirtAddr) -> Result<Area, VmError> {
+    WINDOWS.lock().get(kind).release(start)
+}
+
+/// Returns the kind of window `addr` lies in, if any.
+pub fn kind_of(addr: VirtAddr) -> Option<AreaKind> {
+    WINDOWS.lock().kind_of(addr)
+}
+
+/// Allocate `size` bytes of virtual memory, backed by frames that need not
+/// be contiguous. The memory is writable and is not zeroed.
+///
+/// Meant for large buffers; small allocations belong on the heap.
+pub fn vmalloc(size: usize) -> Result<VirtAddr, VmError> {
+    let area = reserve(AreaKind::Vmalloc, size as u64)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+    let mapped = with_kernel_memory(|memory| map_range(memory, area.start, area.size, flags))
+        .unwrap_or(Err(VmError::NotInitialised));
+    if let Err(err) = mapped {
+        let _ = release(AreaKind::Vmalloc, area.start);
+        return Err(err);
+    }
+    Ok(area.start)
+}
+
+/// Allocate `size` bytes of virtual memory like `vmalloc`, but paged on
+/// demand. The memory reads as zero until written.
+pub fn vmalloc_lazy(size: usize) -> Result<VirtAddr, VmError> {
+    reserve_lazy(AreaKind::Vmalloc, size as u64).map(|area| area.start)
+}
+
+/// Returns the number of page faults resolved by mapping a page of a lazy
+/// area.
+pub fn lazy_faults() -> u64 {
+    LAZY_FAULTS.load(Ordering::Relaxed)
+}
+
+/// Map a zeroed frame at `addr` if it lies in a lazy area and is simply not
+/// mapped yet. Returns whether the faulting access can be retried.
+///
+/// Called by the page fault handler, which may have interrupted a holder of
+/// the area or page table locks, so neither is waited for.
+pub(crate) fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let invalid = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::USER_MODE
+        | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code.intersects(invalid) {
+        return false;
+    }
+
+    let area = WINDOWS.try_lock().and_then(|mut windows| {
+        let kind = windows.kind_of(addr)?;
+        windows.get(kind).find(addr.as_u64())
+    });
+    if !matches!(area, Some(area) if area.lazy) {
+        return false;
+    }
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+    let mapped = matches!(
+        try_with_kernel_memory(|memory| map_page(memory, page, flags)),
+        Some(Ok(()))
+    );
+    if mapped {
+        unsafe {
+            core::ptr::write_bytes(
+                page.start_address().as_mut_ptr::<u8>(),
+                0,
+                PAGE_SIZE as usize,
+            )
+        };
+        LAZY_FAULTS.fetch_add(1, Ordering::Relaxed);
+    }
+    mapped
+}
+
+/// Free memory returned by `vmalloc` or `vmalloc_lazy`, unmapping it and
+/// freeing its frames.
+///
+/// This function is unsafe because the memory must not be used any more.
+pub unsafe fn vfree(addr: VirtAddr) -> Result<(), VmError> {
+    let area = {
+        let mut windows = WINDOWS.lock();
+        let window = windows.get(AreaKind::Vmalloc);
+        window.areas[window.position(addr)?]
+    };
+
+    with_kernel_memory(|memory| unmap_area(memory, area.start, area.size))
+        .ok_or(VmError::NotInitialised)?;
+    release(AreaKind::Vmalloc, addr).map(|_| ())
+}
+
+/// Map every page of the `size` bytes from `start` to a newly allocated
+/// frame, undoing everything if a frame cannot be found.
+pub(crate) fn map_range(
+    memory: &mut KernelMemory,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        let page: Page<Size4KiB> = Page::containing_address(start + offset);
+        if let Err(err) = map_page(memory, page, flags) {
+            unmap_area(memory, start, offset);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Map `page` to a newly allocated frame.
+fn map_page(
+    memory: &mut KernelMemory,
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    let frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frames)
+        .ok_or(VmError::OutOfMemory)?;
+
+    match unsafe { memory.mapper.map_to(page, frame, flags, &mut memory.frames) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { memory.frames.deallocate_frame(frame) };
+            Err(VmError::OutOfMemory)
+        }
+    }
+}
+
+/// Unmap the `size` bytes from `start` and free the frames behind them.
+fn unmap_area(memory: &mut KernelMemory, start: VirtAddr, size: u64) {
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        let page: Page<Size4KiB> = Page::containing_address(start + offset);
+
+        if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+            unsafe { memory.frames.deallocate_frame(frame) };
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Walking the active page tables to find how an address is mapped.
+//!
+//! Unlike the `Translate` implementations of the x86_64 crate, the walk
+//! reports the permissions that actually apply to an access. A page is only
+//! writable or user accessible if every level allows it, and it is not
+//! executable if any level forbids it.
+
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub(super) const IA32_PAT: u32 = 0x277;
+
+/// The PAT index bit of a 2MiB or 1GiB entry. It sits where a 4KiB entry
+/// keeps the lowest bit of its frame address.
+const HUGE_PAT: u64 = 1 << 12;
+
+/// The memory type of a mapping, selected through the page attribute table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Uncacheable,
+    WriteCombining,
+    WriteThrough,
+    WriteProtected,
+    WriteBack,
+    /// Uncacheable, unless the MTRRs say write combining.
+    UncachedMinus,
+    /// A reserved encoding in the PAT.
+    Reserved(u8),
+}
+
+impl fmt::Display for CacheType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheType::Uncacheable => write!(f, "UC"),
+            CacheType::WriteCombining => write!(f, "WC"),
+            CacheType::WriteThrough => write!(f, "WT"),
+            CacheType::WriteProtected => write!(f, "WP"),
+            CacheType::WriteBack => write!(f, "WB"),
+            CacheType::UncachedMinus => write!(f, "UC-"),
+            CacheType::Reserved(entry) => write!(f, "?{}", entry),
+        }
+    }
+}
+
+impl CacheType {
+    fn from_pat(entry: u8) -> Self {
+        match entry {
+            0 => CacheType::Uncacheable,
+            1 => CacheType::WriteCombining,
+            4 => CacheType::WriteThrough,
+            5 => CacheType::WriteProtected,
+            6 => CacheType::WriteBack,
+            7 => CacheType::UncachedMinus,
+            other => CacheType::Reserved(other),
+        }
+    }
+}
+
+/// The permissions and caching that apply to a mapping, combining every
+/// level of the walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveFlags {
+    pub writable: bool,
+    pub user: bool,
+    pub no_execute: bool,
+    /// Whether the translation survives CR3 being reloaded.
+    pub global: bool,
+    pub cache: CacheType,
+}
+
+/// How a virtual address is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// The level of the entry mapping the page: 1 for a 4KiB page, 2 for a
+    /// 2MiB page and 3 for a 1GiB page.
+    pub level: u8,
+    /// The physical address the virtual address maps to.
+    pub phys_addr: PhysAddr,
+    pub flags: EffectiveFlags,
+}
+
+impl Mapping {
+    /// Returns the size of the page, in bytes.
+    pub fn page_size(&self) -> u64 {
+        entry_size(self.level)
+    }
+}
+
+/// Permissions accumulated from the levels above an entry.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Access {
+    writable: bool,
+    user: bool,
+    no_execute: bool,
+}
+
+impl Access {
+    /// Before the level 4 table, nothing is forbidden yet.
+    pub(super) const TOP: Access = Access {
+        writable: true,
+        user: true,
+        no_execute: false,
+    };
+
+    /// The permissions left after going through an entry with `flags`.
+    pub(super) fn through(self, flags: PageTableFlags) -> Access {
+        Access {
+            writable: self.writable && flags.contains(PageTableFlags::WRITABLE),
+            user: self.user && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            no_execute: self.no_execute || flags.contains(PageTableFlags::NO_EXECUTE),
+        }
+    }
+}
+
+/// Returns the number of bytes mapped by an entry at `level`.
+pub(super) fn entry_size(level: u8) -> u64 {
+    4096 << (9 * (level - 1))
+}
+
+/// Returns whether a present entry at `level` maps a page rather than
+/// pointing to the next table. The huge page bit is reserved at level 4.
+pub(super) fn is_leaf(level: u8, entry: &PageTableEntry) -> bool {
+    level == 1 || ((level == 2 || level == 3) && entry.flags().contains(PageTableFlags::HUGE_PAGE))
+}
+
+/// Returns the start of the frame mapped by the leaf `entry` at `level`.
+pub(super) fn leaf_frame(level: u8, entry: &PageTableEntry) -> PhysAddr {
+    // The low address bits of a huge entry hold its PAT bit.
+    entry.addr().align_down(entry_size(level))
+}
+
+/// Returns the flags of the leaf `entry` at `level`, reached with `access`.
+/// `pat` is the value of the page attribute table MSR, from `read_pat`.
+pub(super) fn leaf_flags(
+    level: u8,
+    entry: &PageTableEntry,
+    access: Access,
+    pat: u64,
+) -> EffectiveFlags {
+    let flags = entry.flags();
+    let access = access.through(flags);
+
+    let pat_bit = if level == 1 {
+        // In a 4KiB entry the huge page bit selects the PAT entry.
+        flags.contains(PageTableFlags::HUGE_PAGE)
+    } else {
+        entry.addr().as_u64() & HUGE_PAT != 0
+    };
+
+    EffectiveFlags {
+        writable: access.writable,
+        user: access.user,
+        no_execute: access.no_execute,
+        global: flags.contains(PageTableFlags::GLOBAL),
+        cache: cache_type(flags, pat_bit, pat),
+    }
+}
+
+pub(super) fn read_pat() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() }
+}
+
+/// Walk the active page tables for `addr`, which are reached through the
+/// physical memory mapping at `physical_memory_offset`. Returns `None` if
+/// the address is not mapped.
+///
+/// `visit` is called with the level, index and contents of every entry read
+/// on the way, including a final entry that is not present.
+pub(super) fn walk_with(
+    addr: VirtAddr,
+    physical_memory_offset: VirtAddr,
+    mut visit: impl FnMut(u8, u16, &PageTableEntry),
+) -> Option<Mapping> {
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table_addr = Cr3::read().0.start_address();
+    let mut access = Access::TOP;
+
+    for (i, &index) in indexes.iter().enumerate() {
+        let level = 4 - i as u8;
+        let virt = physical_memory_offset + table_addr.as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+        let entry = &table[index];
+
+        visit(level, u16::from(index), entry);
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if is_leaf(level, entry) {
+            let offset = addr.as_u64() & (entry_size(level) - 1);
+
+            return Some(Mapping {
+                level,
+                phys_addr: leaf_frame(level, entry) + offset,
+                flags: leaf_flags(level, entry, access, read_pat()),
+            });
+        }
+        access = access.through(entry.flags());
+        table_addr = entry.addr();
+    }
+    None
+}
+
+/// Look up the memory type selected by an entry's PWT, PCD and PAT bits in
+/// the page attribute table `pat`.
+fn cache_type(flags: PageTableFlags, pat_bit: bool, pat: u64) -> CacheType {
+    let mut index = 0;
+    if flags.contains(PageTableFlags::WRITE_THROUGH) {
+        index |= 1;
+    }
+    if flags.contains(PageTableFlags::NO_CACHE) {
+        index |= 2;
+    }
+    if pat_bit {
+        index |= 4;
+    }
+
+    CacheType::from_pat((pat >> (index * 8)) as u8 & 0x7)
+}
@@ -0,0 +1,389 @@
+//! CMOS real time clock driver and wall clock.
+//!
+//! The date and time are read from the RTC once at boot. After that the wall
+//! clock is kept by the RTC interrupts on IRQ8: the update-ended interrupt
+//! advances it by a second, and the periodic interrupt counts the fractions
+//! of a second in between. The alarm interrupt drives the alarms in
+//! [`alarm`].
+
+use crate::interrupts::{irq, InterruptIndex};
+use crate::sync::IrqSafeMutex;
+use crate::{print, println};
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+pub mod alarm;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+/// Not standard, but present on most machines including QEMU.
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_UPDATE_ENDED_INTERRUPT: u8 = 1 << 4;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_UPDATE_ENDED: u8 = 1 << 4;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+
+/// The 12 hour clock keeps the PM flag in the top bit of the hours.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Rate selection for the periodic interrupt, giving 32768 >> (rate - 1) Hz.
+const PERIODIC_RATE: u8 = 6;
+
+/// Frequency of the periodic interrupt.
+pub const PERIODIC_HZ: u32 = 32768 >> (PERIODIC_RATE - 1);
+
+/// The CMOS index and data ports.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+/// The index and data ports must be used as a pair, and the interrupt
+/// handler also needs them.
+static CMOS: IrqSafeMutex<Cmos> = IrqSafeMutex::new(
+    "rtc::CMOS",
+    Cmos {
+        index: Port::new(0x70),
+        data: Port::new(0x71),
+    },
+);
+
+/// A calendar date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days since 1970-01-01 of a civil date, valid for any year from 1970.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    // Count years from March so that the leap day is the last of the year.
+    let year = u64::from(year) - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month = u64::from(month);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+impl DateTime {
+    /// Convert seconds since the Unix epoch to a date and time.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = seconds / 86400 + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        let time = seconds % 86400;
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats the date and time as ISO 8601.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The raw time registers, in whatever format the RTC is configured for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+fn read_raw(cmos: &mut Cmos) -> RawTime {
+    while cmos.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    read_registers(cmos)
+}
+
+fn read_registers(cmos: &mut Cmos) -> RawTime {
+    RawTime([
+        cmos.read(REG_SECONDS),
+        cmos.read(REG_MINUTES),
+        cmos.read(REG_HOURS),
+        cmos.read(REG_DAY),
+        cmos.read(REG_MONTH),
+        cmos.read(REG_YEAR),
+        cmos.read(REG_CENTURY),
+    ])
+}
+
+/// Decode the time registers according to the format in status register B.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = raw.0;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = hour & HOURS_PM != 0;
+    let mut hour = decode(hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match decode(century) {
+        century @ 19..=21 => u16::from(century),
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Read the current date and time from the RTC.
+///
+/// The registers are read until two reads in a row agree, so that an update
+/// in the middle of a read cannot produce a torn result.
+pub fn read_rtc() -> DateTime {
+    let mut cmos = CMOS.lock();
+
+    let mut raw = read_raw(&mut cmos);
+    loop {
+        let again = read_raw(&mut cmos);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    decode(raw, cmos.read(REG_STATUS_B))
+}
+
+/// Read the date and time without waiting for an update to finish.
+///
+/// Only for the RTC interrupt handler after an update-ended or alarm
+/// interrupt, as the next update is almost a second away then.
+fn read_after_update(cmos: &mut Cmos) -> DateTime {
+    let raw = read_registers(cmos);
+    decode(raw, cmos.read(REG_STATUS_B))
+}
+
+/// Program the alarm registers with the time of day of `at` and enable the
+/// alarm interrupt, or disable it for `None`.
+fn set_alarm(at: Option<DateTime>) {
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(REG_STATUS_B);
+
+    let at = match at {
+        Some(at) => at,
+        None => {
+            cmos.write(REG_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+            return;
+        }
+    };
+
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let encode = |value: u8| if binary { value } else { to_bcd(value) };
+
+    let hour = if status_b & STATUS_B_24_HOUR != 0 {
+        encode(at.hour)
+    } else {
+        // 0 is 12 AM and 12 is 12 PM.
+        let pm = if at.hour >= 12 { HOURS_PM } else { 0 };
+        encode((at.hour + 11) % 12 + 1) | pm
+    };
+
+    cmos.write(REG_SECONDS_ALARM, encode(at.second));
+    cmos.write(REG_MINUTES_ALARM, encode(at.minute));
+    cmos.write(REG_HOURS_ALARM, hour);
+    cmos.write(REG_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+}
+
+/// Seconds since the Unix epoch, kept by the update-ended interrupt.
+static SECONDS: AtomicU64 = AtomicU64::new(0);
+
+/// Periodic interrupts since the last update-ended interrupt.
+static SUBSECOND_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// A point in wall clock time, like `std::time::SystemTime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemTime(Duration);
+
+/// 1970-01-01T00:00:00Z.
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+impl SystemTime {
+    /// The current wall clock time, with a resolution of one periodic
+    /// interrupt.
+    pub fn now() -> SystemTime {
+        loop {
+            let seconds = SECONDS.load(Ordering::SeqCst);
+            let ticks = SUBSECOND_TICKS.load(Ordering::SeqCst);
+
+            if SECONDS.load(Ordering::SeqCst) == seconds {
+                // The periodic and update-ended interrupts drift apart
+                // slightly, so never let the fraction reach a whole second.
+                let ticks = ticks.min(PERIODIC_HZ - 1);
+                let nanos = u64::from(ticks) * 1_000_000_000 / u64::from(PERIODIC_HZ);
+                return SystemTime(Duration::new(seconds, nanos as u32));
+            }
+        }
+    }
+
+    /// The time elapsed since `earlier`, or `None` if `earlier` is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// The date and time, truncated to the second.
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix(self.0.as_secs())
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.date_time().fmt(f)
+    }
+}
+
+/// Handler for IRQ8. Reading status register C acknowledges the interrupt,
+/// without which the RTC raises no more.
+fn rtc_interrupt_handler(_irq: u8) {
+    let (status_c, alarm_time) = {
+        let mut cmos = CMOS.lock();
+        let status_c = cmos.read(REG_STATUS_C);
+        let alarm_time = (status_c & STATUS_C_ALARM != 0).then(|| read_after_update(&mut cmos));
+        (status_c, alarm_time)
+    };
+
+    if status_c & STATUS_C_UPDATE_ENDED != 0 {
+        SUBSECOND_TICKS.store(0, Ordering::SeqCst);
+        SECONDS.fetch_add(1, Ordering::SeqCst);
+    } else if status_c & STATUS_C_PERIODIC != 0 {
+        SUBSECOND_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    if let Some(now) = alarm_time {
+        alarm::alarm_interrupt(now);
+    }
+}
+
+/// Read the date and time from the RTC to start the wall clock, and enable
+/// the periodic and update-ended interrupts that keep it going.
+pub fn init() {
+    print!("Initialising RTC...");
+
+    let now = read_rtc();
+    SECONDS.store(now.to_unix(), Ordering::SeqCst);
+
+    irq::register_irq(InterruptIndex::RTC.irq(), rtc_interrupt_handler)
+        .expect("failed to register the RTC interrupt");
+
+    let mut cmos = CMOS.lock();
+
+    let status_a = cmos.read(REG_STATUS_A);
+    cmos.write(REG_STATUS_A, (status_a & 0xf0) | PERIODIC_RATE);
+
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(
+        REG_STATUS_B,
+        status_b | STATUS_B_PERIODIC_INTERRUPT | STATUS_B_UPDATE_ENDED_INTERRUPT,
+    );
+
+    // Clear anything already pending, or the RTC will not interrupt.
+    cmos.read(REG_STATUS_C);
+
+    println!("[ok] {}", now);
+}
+
+#[test_case]
+fn test_unix_time_round_trip() {
+    let date = DateTime {
+        year: 2000,
+        month: 3,
+        day: 1,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+
+    assert_eq!(date.to_unix(), 951_914_096);
+    assert_eq!(DateTime::from_unix(date.to_unix()), date);
+}
@@ -0,0 +1,180 @@
+//! RTC alarms.
+//!
+//! The CMOS alarm registers only hold a time of day, so the RTC is armed
+//! with the time of day of the earliest pending alarm. When it goes off,
+//! every alarm that is due runs and the RTC is armed for the next one. An
+//! alarm more than a day away just lets the hardware alarm go off in between.
+
+use super::{read_rtc, DateTime};
+use crate::sync::IrqSafeMutex;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+/// Number of alarms that can be pending at once.
+pub const MAX_ALARMS: usize = 8;
+
+/// Identifies a scheduled alarm, for cancelling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmError {
+    /// The time has already passed.
+    InPast,
+    /// `MAX_ALARMS` alarms are already pending.
+    NoFreeSlot,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    /// Call a function from the RTC interrupt handler.
+    Call(fn()),
+    /// Wake the task waiting on the alarm's slot.
+    Wake,
+}
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    id: AlarmId,
+    /// Seconds since the Unix epoch.
+    at: u64,
+    action: Action,
+}
+
+/// Pending alarms.
+static ALARMS: IrqSafeMutex<[Option<Alarm>; MAX_ALARMS]> =
+    IrqSafeMutex::new("rtc::alarm::ALARMS", [None; MAX_ALARMS]);
+
+/// Wakers of the tasks waiting on each slot.
+static WAKERS: [AtomicWaker; MAX_ALARMS] = [const { AtomicWaker::new() }; MAX_ALARMS];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Program the RTC for the earliest pending alarm, or disable the alarm
+/// interrupt if there is none.
+fn arm(alarms: &[Option<Alarm>; MAX_ALARMS]) {
+    let next = alarms.iter().flatten().map(|alarm| alarm.at).min();
+
+    super::set_alarm(next.map(DateTime::from_unix));
+}
+
+fn insert(at: DateTime, action: Action) -> Result<(AlarmId, usize), AlarmError> {
+    let at = at.to_unix();
+    if at <= read_rtc().to_unix() {
+        return Err(AlarmError::InPast);
+    }
+
+    let mut alarms = ALARMS.lock();
+
+    let slot = alarms
+        .iter()
+        .position(Option::is_none)
+        .ok_or(AlarmError::NoFreeSlot)?;
+    let id = AlarmId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    alarms[slot] = Some(Alarm { id, at, action });
+
+    arm(&alarms);
+    Ok((id, slot))
+}
+
+/// Call `callback` once the wall clock reaches `at`.
+///
+/// The callback runs in the RTC interrupt handler, so it must be short and
+/// must not take locks that are held with interrupts enabled.
+pub fn schedule(at: DateTime, callback: fn()) -> Result<AlarmId, AlarmError> {
+    insert(at, Action::Call(callback)).map(|(id, _)| id)
+}
+
+/// Cancel a pending alarm. Returns false if it has already gone off.
+pub fn cancel(id: AlarmId) -> bool {
+    let mut alarms = ALARMS.lock();
+
+    let slot = alarms
+        .iter_mut()
+        .find(|slot| matches!(slot, Some(alarm) if alarm.id == id));
+    match slot {
+        Some(slot) => {
+            *slot = None;
+            arm(&alarms);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns a future that completes once the wall clock reaches `at`.
+///
+/// The future completes immediately if `at` has already passed. Dropping it
+/// cancels the alarm.
+pub fn wait_until(at: DateTime) -> Result<AlarmFuture, AlarmError> {
+    match insert(at, Action::Wake) {
+        Ok(alarm) => Ok(AlarmFuture { alarm: Some(alarm) }),
+        Err(AlarmError::InPast) => Ok(AlarmFuture { alarm: None }),
+        Err(error) => Err(error),
+    }
+}
+
+/// A future waiting for an alarm, created by `wait_until`.
+pub struct AlarmFuture {
+    /// The alarm and its slot, or `None` once it has gone off.
+    alarm: Option<(AlarmId, usize)>,
+}
+
+impl Future for AlarmFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let (id, slot) = match self.alarm {
+            Some(alarm) => alarm,
+            None => return Poll::Ready(()),
+        };
+
+        // Register before checking, so an alarm going off in between still
+        // wakes the task.
+        WAKERS[slot].register(cx.waker());
+
+        let pending = ALARMS.lock()[slot].is_some_and(|alarm| alarm.id == id);
+        if pending {
+            Poll::Pending
+        } else {
+            self.alarm = None;
+            Poll::Ready(())
+        }
+    }
+}
+
+impl Drop for AlarmFuture {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.alarm {
+            cancel(id);
+        }
+    }
+}
+
+/// Run every alarm that is due at `now` and arm the RTC for the next one.
+/// Called from the RTC interrupt handler.
+pub(super) fn alarm_interrupt(now: DateTime) {
+    let now = now.to_unix();
+    let mut due = [None; MAX_ALARMS];
+
+    {
+        let mut alarms = ALARMS.lock();
+        for (slot, alarm) in alarms.iter_mut().enumerate() {
+            if matches!(alarm, Some(alarm) if alarm.at <= now) {
+                due[slot] = alarm.take();
+            }
+        }
+        arm(&alarms);
+    }
+
+    for (slot, alarm) in due.iter().enumerate() {
+        match alarm.map(|alarm| alarm.action) {
+            Some(Action::Call(callback)) => callback(),
+            Some(Action::Wake) => WAKERS[slot].wake(),
+            None => {}
+        }
+    }
+}
@@ -1,14 +1,23 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use uart_16550::SerialPort;
 
 // static print with a spin lock.
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         // unsafe part of the function uses port address 0x3F8.
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new("SERIAL1", serial_port)
+    };
+
+    /// The second serial port, used by the GDB stub so that it does not
+    /// interfere with the kernel output on the first.
+    pub static ref SERIAL2: IrqSafeMutex<SerialPort> = {
+        // unsafe part of the function uses port address 0x2F8.
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        IrqSafeMutex::new("SERIAL2", serial_port)
     };
 }
 
@@ -32,12 +41,9 @@ macro_rules! serial_println {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
@@ -0,0 +1,321 @@
+//! Spin locks that know about interrupts.
+//!
+//! `IrqSafeMutex` disables interrupts for as long as it is held, so data
+//! shared with interrupt handlers can be locked without wrapping every use
+//! in `without_interrupts`. `Mutex` leaves interrupts alone and is for data
+//! that interrupt handlers never touch.
+//!
+//! Both count their acquisitions, how often and how long they had to spin,
+//! and the longest they were held, which `report` prints for the kernel's
+//! main locks.
+//!
+//! With the `lockdep` feature, every acquisition of either lock is checked
+//! by [`lockdep`] for lock order inversions and for locks taken both in
+//! interrupt handlers and with interrupts enabled.
+
+use crate::time::tsc;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+
+/// Identifies a lock to the lock order checker, and keeps its statistics.
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+    /// Index assigned by the checker, plus one, or zero until the lock is
+    /// first taken.
+    #[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+    id: AtomicU8,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+    max_hold_cycles: AtomicU64,
+    held: AtomicBool,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        LockClass {
+            name,
+            id: AtomicU8::new(0),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+            max_hold_cycles: AtomicU64::new(0),
+            held: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the statistics gathered since boot or the last `reset_stats`.
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            name: self.name,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+            max_hold_cycles: self.max_hold_cycles.load(Ordering::Relaxed),
+            held: self.held.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.spins.store(0, Ordering::Relaxed);
+        self.max_hold_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Statistics for one lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockStats {
+    pub name: &'static str,
+    /// Times the lock was taken.
+    pub acquisitions: u64,
+    /// Times the lock was already held when it was wanted.
+    pub contended: u64,
+    /// Total spin loop iterations waiting for the lock.
+    pub spins: u64,
+    /// Longest the lock was held, in TSC cycles.
+    pub max_hold_cycles: u64,
+    /// Whether the lock was held when the statistics were taken.
+    pub held: bool,
+}
+
+/// Take `inner`, spinning until it is free and recording the statistics.
+fn spin_lock<'a, T>(inner: &'a spin::Mutex<T>, class: &LockClass) -> spin::MutexGuard<'a, T> {
+    let mut spins = 0;
+    let guard = loop {
+        match inner.try_lock() {
+            Some(guard) => break guard,
+            None => {
+                spins += 1;
+                core::hint::spin_loop();
+            }
+        }
+    };
+
+    class.acquisitions.fetch_add(1, Ordering::Relaxed);
+    if spins != 0 {
+        class.contended.fetch_add(1, Ordering::Relaxed);
+        class.spins.fetch_add(spins, Ordering::Relaxed);
+    }
+    guard
+}
+
+/// A spin lock that disables interrupts while it is held, restoring the
+/// previous interrupt state when it is released.
+///
+/// When several are held at once they must be released in the reverse order
+/// they were taken, or interrupts are enabled again too early.
+#[derive(Debug)]
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+    class: LockClass,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a lock reported as `name` by the lock order checker.
+    pub const fn new(name: &'static str, value: T) -> Self {
+        IrqSafeMutex {
+            inner: spin::Mutex::new(value),
+            class: LockClass::new(name),
+        }
+    }
+
+    /// Disable interrupts, then take the lock.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        acquire(&self.class, false);
+        MutexGuard::new(
+            spin_lock(&self.inner, &self.class),
+            &self.class,
+            were_enabled,
+        )
+    }
+
+    /// Take the lock if it is free, leaving interrupts as they were if not.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                acquire(&self.class, false);
+                self.class.acquisitions.fetch_add(1, Ordering::Relaxed);
+                Some(MutexGuard::new(guard, &self.class, were_enabled))
+            }
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Release the lock without a guard.
+    ///
+    /// This is unsafe because whoever holds the lock may still be using the
+    /// data. It is meant for crash and debugger paths that interrupted the
+    /// holder, which will never run again or not until they are done.
+    pub unsafe fn force_unlock(&self) {
+        release(&self.class);
+        self.class.held.store(false, Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.class.stats()
+    }
+}
+
+/// A spin lock that leaves interrupts enabled while it is held.
+///
+/// Taking one in an interrupt handler can deadlock against the code the
+/// interrupt arrived in, so use `IrqSafeMutex` for anything a handler needs.
+#[derive(Debug)]
+pub struct Mutex<T> {
+    inner: spin::Mutex<T>,
+    class: LockClass,
+}
+
+impl<T> Mutex<T> {
+    /// Create a lock reported as `name` by the lock order checker.
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Mutex {
+            inner: spin::Mutex::new(value),
+            class: LockClass::new(name),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        acquire(&self.class, interrupts::are_enabled());
+        MutexGuard::new(spin_lock(&self.inner, &self.class), &self.class, false)
+    }
+
+    /// Take the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        acquire(&self.class, interrupts::are_enabled());
+        self.class.acquisitions.fetch_add(1, Ordering::Relaxed);
+        Some(MutexGuard::new(guard, &self.class, false))
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.class.stats()
+    }
+}
+
+/// Holds either kind of lock until dropped.
+pub struct MutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    class: &'a LockClass,
+    /// Whether interrupts were enabled before an `IrqSafeMutex` was taken.
+    restore_interrupts: bool,
+    /// TSC when the lock was taken.
+    acquired_at: u64,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(guard: spin::MutexGuard<'a, T>, class: &'a LockClass, restore_interrupts: bool) -> Self {
+        class.held.store(true, Ordering::Relaxed);
+        MutexGuard {
+            guard: ManuallyDrop::new(guard),
+            class,
+            restore_interrupts,
+            acquired_at: tsc::read(),
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let held = tsc::read().wrapping_sub(self.acquired_at);
+        self.class
+            .max_hold_cycles
+            .fetch_max(held, Ordering::Relaxed);
+        self.class.held.store(false, Ordering::Relaxed);
+
+        // Interrupts must stay off until the lock itself is free.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        release(self.class);
+
+        if self.restore_interrupts {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+use lockdep::{acquire, release};
+
+#[cfg(not(feature = "lockdep"))]
+#[inline(always)]
+fn acquire(_class: &LockClass, _irqs_enabled: bool) {}
+
+#[cfg(not(feature = "lockdep"))]
+#[inline(always)]
+fn release(_class: &LockClass) {}
+
+/// Statistics for the kernel's main locks, printed as a table.
+pub struct Report([LockStats; 5]);
+
+/// Returns the statistics for the kernel's main locks.
+pub fn report() -> Report {
+    Report([
+        crate::allocator::lock_stats(),
+        crate::VGA_BUFFER::WRITER.stats(),
+        crate::serial::SERIAL1.stats(),
+        crate::serial::SERIAL2.stats(),
+        crate::interrupts::PICS.stats(),
+    ])
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<12} {:>4} {:>12} {:>10} {:>12} {:>16}",
+            "LOCK", "HELD", "ACQUIRED", "CONTENDED", "SPINS", "MAX HOLD CYCLES"
+        )?;
+        for stats in &self.0 {
+            write!(
+                f,
+                "\n{:<12} {:>4} {:>12} {:>10} {:>12} {:>16}",
+                stats.name,
+                if stats.held { "yes" } else { "no" },
+                stats.acquisitions,
+                stats.contended,
+                stats.spins,
+                stats.max_hold_cycles
+            )?;
+        }
+        Ok(())
+    }
+}
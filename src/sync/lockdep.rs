@@ -0,0 +1,221 @@
+//! Lock order and interrupt safety checking.
+//!
+//! Every lock acquisition is recorded, building a graph of which locks have
+//! been taken while holding which. Taking a lock that has, directly or
+//! through other locks, been taken before one that is currently held means
+//! two paths take the same locks in opposite orders, which can deadlock. A
+//! lock taken in an interrupt handler that is also held with interrupts
+//! enabled can deadlock against itself on a single CPU.
+//!
+//! Each problem is reported once over the raw serial port, without taking
+//! any locks, and execution continues.
+
+use super::LockClass;
+use crate::crash::RawSerial;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of distinct locks that can be tracked. Locks beyond this are not
+/// checked.
+const MAX_CLASSES: usize = 64;
+
+/// Depth of the held lock stack.
+const MAX_HELD: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Usage {
+    in_interrupt: bool,
+    with_interrupts_enabled: bool,
+    reported: bool,
+}
+
+struct State {
+    names: [&'static str; MAX_CLASSES],
+    usage: [Usage; MAX_CLASSES],
+    classes: usize,
+    /// Bit `b` of `after[a]` is set once `b` has been taken while holding `a`.
+    after: [u64; MAX_CLASSES],
+    held: [u8; MAX_HELD],
+    depth: usize,
+}
+
+static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+    names: [""; MAX_CLASSES],
+    usage: [Usage {
+        in_interrupt: false,
+        with_interrupts_enabled: false,
+        reported: false,
+    }; MAX_CLASSES],
+    classes: 0,
+    after: [0; MAX_CLASSES],
+    held: [0; MAX_HELD],
+    depth: 0,
+});
+
+/// Set while the checker itself is running, so that locks taken while
+/// reporting are not checked.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// A problem found while recording an acquisition.
+enum Report {
+    Recursive(&'static str),
+    Inversion {
+        held: &'static str,
+        taking: &'static str,
+    },
+    InterruptUnsafe(&'static str),
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Report::Recursive(name) => {
+                write!(f, "lock '{}' taken while already held", name)
+            }
+            Report::Inversion { held, taking } => write!(
+                f,
+                "lock '{}' taken while holding '{}', but '{}' has been taken \
+                 while holding '{}' before",
+                taking, held, held, taking
+            ),
+            Report::InterruptUnsafe(name) => write!(
+                f,
+                "lock '{}' is taken in interrupt handlers and also held with \
+                 interrupts enabled",
+                name
+            ),
+        }
+    }
+}
+
+fn report(report: &Report) {
+    // The lock around `SERIAL1` may be the one being reported.
+    let mut serial = RawSerial::com1();
+    let _ = writeln!(serial, "LOCKDEP: {}", report);
+    let _ = writeln!(serial, "{}", crate::debug::backtrace::Backtrace::current());
+}
+
+impl State {
+    fn class_id(&mut self, class: &LockClass) -> Option<usize> {
+        let id = class.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return Some(usize::from(id - 1));
+        }
+        if self.classes == MAX_CLASSES {
+            return None;
+        }
+
+        let id = self.classes;
+        self.classes += 1;
+        self.names[id] = class.name;
+        class.id.store(id as u8 + 1, Ordering::Relaxed);
+        Some(id)
+    }
+
+    /// Returns whether `to` has been taken while holding `from`, directly or
+    /// through other locks.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = 1u64 << from;
+        let mut frontier = self.after[from];
+
+        while frontier & !visited != 0 {
+            let next = (frontier & !visited).trailing_zeros() as usize;
+            if next == to {
+                return true;
+            }
+            visited |= 1 << next;
+            frontier |= self.after[next];
+        }
+        false
+    }
+
+    fn acquire(&mut self, id: usize, irqs_enabled: bool) -> Option<Report> {
+        let mut found = None;
+
+        let usage = &mut self.usage[id];
+        if crate::interrupts::irq::in_interrupt() {
+            usage.in_interrupt = true;
+        }
+        if irqs_enabled {
+            usage.with_interrupts_enabled = true;
+        }
+        if usage.in_interrupt && usage.with_interrupts_enabled && !usage.reported {
+            usage.reported = true;
+            found = Some(Report::InterruptUnsafe(self.names[id]));
+        }
+
+        for &held in &self.held[..self.depth] {
+            let held = usize::from(held);
+            if held == id {
+                found = Some(Report::Recursive(self.names[id]));
+                continue;
+            }
+            // Only a new dependency can introduce a new cycle.
+            if self.after[held] & (1 << id) == 0 {
+                if self.reaches(id, held) {
+                    found = Some(Report::Inversion {
+                        held: self.names[held],
+                        taking: self.names[id],
+                    });
+                }
+                self.after[held] |= 1 << id;
+            }
+        }
+
+        if self.depth < MAX_HELD {
+            self.held[self.depth] = id as u8;
+            self.depth += 1;
+        }
+        found
+    }
+
+    fn release(&mut self, id: usize) {
+        // Locks are not always released in the reverse order they were taken.
+        if let Some(index) = self.held[..self.depth]
+            .iter()
+            .rposition(|&held| usize::from(held) == id)
+        {
+            self.held.copy_within(index + 1..self.depth, index);
+            self.depth -= 1;
+        }
+    }
+}
+
+/// Record that `class` is about to be taken. `irqs_enabled` is whether
+/// interrupts stay enabled while it is held.
+pub(super) fn acquire(class: &LockClass, irqs_enabled: bool) {
+    without_interrupts(|| {
+        if BUSY.swap(true, Ordering::Acquire) {
+            return;
+        }
+
+        let found = {
+            let mut state = STATE.lock();
+            state
+                .class_id(class)
+                .and_then(|id| state.acquire(id, irqs_enabled))
+        };
+        if let Some(found) = found {
+            report(&found);
+        }
+
+        BUSY.store(false, Ordering::Release);
+    });
+}
+
+/// Record that `class` has been released.
+pub(super) fn release(class: &LockClass) {
+    let id = class.id.load(Ordering::Relaxed);
+    if id == 0 {
+        return;
+    }
+
+    without_interrupts(|| {
+        if BUSY.swap(true, Ordering::Acquire) {
+            return;
+        }
+        STATE.lock().release(usize::from(id - 1));
+        BUSY.store(false, Ordering::Release);
+    });
+}
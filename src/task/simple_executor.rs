@@ -1,4 +1,5 @@
 use super::Task;
+use crate::debug::watchdog;
 use alloc::collections::VecDeque;
 use core::task::{Context, RawWaker, RawWakerVTable, Waker, Poll};
 
@@ -23,7 +24,9 @@ impl SimpleExecutor {
     }
 
     pub fn run(&mut self){
+        watchdog::set_executor_running(true);
         while let Some(mut task) = self.task_queue.pop_front(){
+            watchdog::executor_heartbeat();
             let waker = dummy_waker();
             let mut context = Context::from_waker(&waker);
 
@@ -32,6 +35,7 @@ impl SimpleExecutor {
                 Poll::Pending => self.task_queue.push_back(task) 
             }
         }
+        watchdog::set_executor_running(false);
     }
 }
 
@@ -0,0 +1,221 @@
+//! High resolution monotonic time.
+//!
+//! Time is measured with the invariant TSC when the CPU has one, calibrated
+//! against the HPET or the PIT. Without an invariant TSC the HPET main
+//! counter is read directly, and as a last resort a TSC that may change
+//! speed is used anyway. The clock only runs once `init` has been called,
+//! which must be after `memory::install` to find the HPET.
+
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::time::Duration;
+
+pub mod hpet;
+pub(crate) mod pit;
+pub mod timer;
+pub mod tsc;
+
+use hpet::Hpet;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A counter that time can be measured with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clocksource {
+    /// The time stamp counter.
+    Tsc,
+    /// The main counter of the high precision event timer.
+    Hpet,
+}
+
+impl fmt::Display for Clocksource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clocksource::Tsc => write!(f, "tsc"),
+            Clocksource::Hpet => write!(f, "hpet"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    /// `init` or `init_with` has already been called.
+    AlreadyInitialised,
+    /// The requested clocksource is not present.
+    Unavailable,
+}
+
+#[derive(Debug)]
+enum Counter {
+    Tsc,
+    Hpet(Hpet),
+}
+
+#[derive(Debug)]
+struct Clock {
+    counter: Counter,
+    frequency: u64,
+    /// Counter value at `init`, which is time zero for every `Instant`.
+    start: u64,
+}
+
+impl Clock {
+    fn read(&self) -> u64 {
+        match &self.counter {
+            Counter::Tsc => tsc::read(),
+            Counter::Hpet(hpet) => hpet.counter(),
+        }
+    }
+
+    fn source(&self) -> Clocksource {
+        match self.counter {
+            Counter::Tsc => Clocksource::Tsc,
+            Counter::Hpet(_) => Clocksource::Hpet,
+        }
+    }
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+
+fn start(counter: Counter, frequency: u64) -> Result<Clocksource, TimeError> {
+    let mut clock = Clock {
+        counter,
+        frequency,
+        start: 0,
+    };
+    clock.start = clock.read();
+    let source = clock.source();
+
+    CLOCK
+        .try_init_once(|| clock)
+        .map_err(|_| TimeError::AlreadyInitialised)?;
+    Ok(source)
+}
+
+/// Start the clock with the best clocksource available: an invariant TSC,
+/// then the HPET, then any TSC.
+pub fn init() -> Result<Clocksource, TimeError> {
+    let hpet = Hpet::init();
+
+    match hpet {
+        Some(hpet) if !tsc::is_invariant() => {
+            let frequency = hpet.frequency();
+            start(Counter::Hpet(hpet), frequency)
+        }
+        _ => start(Counter::Tsc, tsc::calibrate(hpet.as_ref())),
+    }
+}
+
+/// Start the clock with a particular clocksource.
+pub fn init_with(source: Clocksource) -> Result<Clocksource, TimeError> {
+    let hpet = Hpet::init();
+
+    match source {
+        Clocksource::Tsc => start(Counter::Tsc, tsc::calibrate(hpet.as_ref())),
+        Clocksource::Hpet => {
+            let hpet = hpet.ok_or(TimeError::Unavailable)?;
+            let frequency = hpet.frequency();
+            start(Counter::Hpet(hpet), frequency)
+        }
+    }
+}
+
+/// Returns the clocksource in use, or `None` before `init`.
+pub fn clocksource() -> Option<Clocksource> {
+    CLOCK.try_get().ok().map(Clock::source)
+}
+
+/// Returns the frequency of the clocksource in Hz, or `None` before `init`.
+pub fn frequency() -> Option<u64> {
+    CLOCK.try_get().ok().map(|clock| clock.frequency)
+}
+
+/// A measurement of the monotonic clock, like `std::time::Instant`.
+///
+/// Instants count nanoseconds from `init`. Before then, every instant is
+/// zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Instant {
+        let clock = match CLOCK.try_get() {
+            Ok(clock) => clock,
+            Err(_) => return Instant(0),
+        };
+
+        let ticks = clock.read().saturating_sub(clock.start);
+        let nanos = u128::from(ticks) * NANOS_PER_SECOND / u128::from(clock.frequency);
+        Instant(nanos as u64)
+    }
+
+    /// Time elapsed since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Time elapsed since `earlier`, or `None` if `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Time elapsed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+
+    /// Nanoseconds since `init`.
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
@@ -0,0 +1,112 @@
+//! The high precision event timer, found through the ACPI HPET table.
+//!
+//! Only the main counter is used, as a clocksource. The registers are
+//! mapped uncached with `map_mmio`, so the HPET can only be found after
+//! `memory::install`.
+
+use super::pit;
+use crate::acpi;
+use crate::memory::{self, MmioRegion};
+use x86_64::PhysAddr;
+
+/// Offset of the generic address structure holding the registers' address
+/// in the HPET table. It follows the 36 byte header and the 4 byte hardware
+/// block id.
+const TABLE_ADDRESS: u64 = 40;
+/// Offset of the address space id in the generic address structure.
+const ADDRESS_SPACE: u64 = 0;
+/// Offset of the address in the generic address structure.
+const ADDRESS: u64 = 4;
+/// The address space id of system memory.
+const SYSTEM_MEMORY: u8 = 0;
+
+/// Size of the general registers, which include the main counter.
+const REGISTERS_SIZE: usize = 0x100;
+
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIGURATION: usize = 0x10;
+const REG_MAIN_COUNTER: usize = 0xf0;
+
+const CAPABILITY_64_BIT_COUNTER: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The longest counter period the specification allows, 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// How long the counter is watched for movement, as a PIT rate: 1ms.
+const SANITY_CHECK_HZ: u32 = 1000;
+
+/// An enabled HPET.
+#[derive(Debug)]
+pub struct Hpet {
+    registers: MmioRegion,
+    /// Length of a counter tick in femtoseconds.
+    period_fs: u64,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        self.registers.write(register, value)
+    }
+
+    /// Find the HPET and start its main counter. Returns `None` if there is
+    /// no HPET, its registers are not in memory, their address is invalid or
+    /// cannot be mapped, its counter is only 32 bits, which would wrap
+    /// within minutes, or the counter does not move once started.
+    pub fn init() -> Option<Hpet> {
+        let table = acpi::find_table(b"HPET")?;
+        let offset = memory::physical_memory_offset()?;
+        let address = offset + table.as_u64() + TABLE_ADDRESS;
+
+        let space = unsafe { (address + ADDRESS_SPACE).as_ptr::<u8>().read() };
+        if space != SYSTEM_MEMORY {
+            return None;
+        }
+        let base_ptr = (address + ADDRESS).as_ptr::<u64>();
+        let base = PhysAddr::try_new(unsafe { base_ptr.read_unaligned() }).ok()?;
+
+        let mut hpet = Hpet {
+            registers: unsafe { memory::map_mmio(base, REGISTERS_SIZE) }.ok()?,
+            period_fs: 0,
+        };
+        let capabilities = hpet.read(REG_CAPABILITIES);
+        if capabilities & CAPABILITY_64_BIT_COUNTER == 0 {
+            return None;
+        }
+        hpet.period_fs = capabilities >> 32;
+        if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+            return None;
+        }
+
+        let configuration = hpet.read(REG_CONFIGURATION);
+        hpet.write(REG_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+
+        hpet.is_counting().then_some(hpet)
+    }
+
+    /// Returns whether the main counter moves over a short PIT countdown.
+    fn is_counting(&self) -> bool {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            pit::start_countdown(SANITY_CHECK_HZ);
+            let start = self.counter();
+            pit::wait_countdown();
+            self.counter() != start
+        })
+    }
+
+    /// The current value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    /// Frequency of the main counter.
+    pub fn frequency(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period_fs
+    }
+}
@@ -0,0 +1,76 @@
+//! The programmable interval timer.
+//!
+//! Channel 0 drives IRQ0 while the PICs are in use. Channel 2 is the only
+//! channel whose output can be polled, through system control port B, so it
+//! is used to calibrate the other clocks against the PIT's fixed frequency.
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock.
+pub const PIT_HZ: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CONTROL_PORT_B: u16 = 0x61;
+
+const GATE_2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT_2: u8 = 1 << 5;
+
+/// Program channel 0 to raise IRQ0 `hz` times a second.
+///
+/// `hz` must be at least 19, as the count is only 16 bits.
+pub(crate) fn start_periodic(hz: u32) {
+    let count = PIT_HZ / hz;
+    debug_assert!(count <= u32::from(u16::MAX));
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
+
+    unsafe {
+        // Channel 0, low then high byte, mode 3 (square wave).
+        command.write(0b0011_0110);
+        channel_0.write(count as u8);
+        channel_0.write((count >> 8) as u8);
+    }
+}
+
+/// Start channel 2 counting down for `1 / hz` seconds.
+///
+/// `hz` must be at least 19, as the count is only 16 bits.
+pub(crate) fn start_countdown(hz: u32) {
+    let count = PIT_HZ / hz;
+    debug_assert!(count <= u32::from(u16::MAX));
+
+    let mut control: Port<u8> = Port::new(CONTROL_PORT_B);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
+
+    unsafe {
+        // Enable the channel 2 gate with the speaker off.
+        let value = control.read();
+        control.write((value & !SPEAKER) | GATE_2);
+
+        // Channel 2, low then high byte, mode 0 (interrupt on terminal count).
+        // Counting starts once the high byte is written.
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+    }
+}
+
+/// Returns whether the countdown started by `start_countdown` has run out.
+pub(crate) fn countdown_done() -> bool {
+    let mut control: Port<u8> = Port::new(CONTROL_PORT_B);
+
+    // The channel 2 output goes high once the count has run out.
+    unsafe { control.read() & OUTPUT_2 != 0 }
+}
+
+/// Wait for the countdown started by `start_countdown` to run out.
+pub(crate) fn wait_countdown() {
+    while !countdown_done() {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,205 @@
+//! Kernel timers, with callbacks run from the timer interrupt.
+//!
+//! Timers count in ticks of IRQ0, which fires `TICK_HZ` times a second
+//! whether it is driven by the PIT or the local APIC timer. Armed timers are
+//! kept in a queue sorted by deadline, so each tick only looks at the front.
+//!
+//! Callbacks run in the timer interrupt handler, on the interrupted CPU,
+//! with interrupts disabled. They run after the queue lock is released, so
+//! a callback may arm or cancel any timer, including its own, but it must be
+//! short and must not take locks that are held with interrupts enabled.
+
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Frequency of the timer interrupt.
+pub const TICK_HZ: u32 = 100;
+
+/// Number of timers that can be armed at once.
+pub const MAX_TIMERS: usize = 32;
+
+/// Timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Convert a duration to ticks, rounding up so that a timer never fires
+/// early. A timer always waits at least one tick.
+fn to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / u128::from(TICK_HZ);
+    let ticks = duration.as_nanos().div_ceil(nanos_per_tick);
+
+    u64::try_from(ticks).unwrap_or(u64::MAX).max(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// `MAX_TIMERS` timers are already armed.
+    QueueFull,
+}
+
+/// A timer calling a function once or periodically.
+///
+/// Timers are statics, so the queue can refer to them without allocating:
+///
+/// ```ignore
+/// static BLINK: KernelTimer = KernelTimer::new(toggle_cursor);
+/// BLINK.arm_periodic(Duration::from_millis(500))?;
+/// ```
+pub struct KernelTimer {
+    callback: fn(),
+}
+
+impl KernelTimer {
+    pub const fn new(callback: fn()) -> Self {
+        KernelTimer { callback }
+    }
+
+    /// Call the callback once, after `delay`. Re-arms the timer if it is
+    /// already armed.
+    pub fn arm_oneshot(&'static self, delay: Duration) -> Result<(), TimerError> {
+        self.arm(to_ticks(delay), None)
+    }
+
+    /// Call the callback every `period`, starting one period from now.
+    /// Re-arms the timer if it is already armed.
+    pub fn arm_periodic(&'static self, period: Duration) -> Result<(), TimerError> {
+        let period = to_ticks(period);
+        self.arm(period, Some(period))
+    }
+
+    fn arm(&'static self, delay: u64, period: Option<u64>) -> Result<(), TimerError> {
+        let mut queue = QUEUE.lock();
+
+        queue.remove(self);
+        queue.insert(Entry {
+            deadline: ticks().saturating_add(delay),
+            period,
+            timer: self,
+        })
+    }
+
+    /// Disarm the timer. Returns false if it was not armed.
+    ///
+    /// Once this returns the callback will not be called again, unless it is
+    /// running on the interrupted code's stack right now.
+    pub fn cancel(&'static self) -> bool {
+        QUEUE.lock().remove(self)
+    }
+
+    /// Returns whether the timer is armed.
+    pub fn is_armed(&'static self) -> bool {
+        QUEUE.lock().position(self).is_some()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The tick the timer fires on.
+    deadline: u64,
+    /// Ticks between firings of a periodic timer.
+    period: Option<u64>,
+    timer: &'static KernelTimer,
+}
+
+/// Armed timers, sorted by deadline. Timers with the same deadline fire in
+/// the order they were armed.
+struct TimerQueue {
+    entries: [Option<Entry>; MAX_TIMERS],
+    len: usize,
+}
+
+impl TimerQueue {
+    fn position(&self, timer: &KernelTimer) -> Option<usize> {
+        self.entries[..self.len]
+            .iter()
+            .position(|entry| matches!(entry, Some(e) if core::ptr::eq(e.timer, timer)))
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<(), TimerError> {
+        if self.len == MAX_TIMERS {
+            return Err(TimerError::QueueFull);
+        }
+
+        let index = self.entries[..self.len]
+            .iter()
+            .position(|e| matches!(e, Some(e) if e.deadline > entry.deadline))
+            .unwrap_or(self.len);
+        self.entries[index..=self.len].rotate_right(1);
+        self.entries[index] = Some(entry);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, timer: &KernelTimer) -> bool {
+        match self.position(timer) {
+            Some(index) => {
+                self.entries[index..self.len].rotate_left(1);
+                self.len -= 1;
+                self.entries[self.len] = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the first timer if it is due at `now`, re-queueing it if it is
+    /// periodic.
+    fn pop_due(&mut self, now: u64) -> Option<&'static KernelTimer> {
+        let entry = self.entries[0].filter(|entry| entry.deadline <= now)?;
+        self.remove(entry.timer);
+
+        if let Some(period) = entry.period {
+            // Skip any periods that were missed, rather than firing in a burst.
+            let deadline = entry.deadline.saturating_add(period).max(now + 1);
+            // The entry was just removed, so there is room for it.
+            let _ = self.insert(Entry { deadline, ..entry });
+        }
+        Some(entry.timer)
+    }
+}
+
+static QUEUE: IrqSafeMutex<TimerQueue> = IrqSafeMutex::new(
+    "time::timer::QUEUE",
+    TimerQueue {
+        entries: [None; MAX_TIMERS],
+        len: 0,
+    },
+);
+
+/// Advance the tick count and run every timer that is due. Called from the
+/// timer interrupt handler.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    loop {
+        let timer = match QUEUE.lock().pop_due(now) {
+            Some(timer) => timer,
+            None => break,
+        };
+        (timer.callback)();
+    }
+}
+
+#[test_case]
+fn test_oneshot_timer_fires() {
+    use core::sync::atomic::AtomicBool;
+
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    static TIMER: KernelTimer = KernelTimer::new(|| FIRED.store(true, Ordering::SeqCst));
+
+    TIMER.arm_oneshot(Duration::from_millis(20)).unwrap();
+    assert!(TIMER.is_armed());
+
+    let give_up = ticks() + u64::from(TICK_HZ);
+    while !FIRED.load(Ordering::SeqCst) && ticks() < give_up {
+        x86_64::instructions::hlt();
+    }
+
+    assert!(FIRED.load(Ordering::SeqCst));
+    assert!(!TIMER.is_armed());
+}
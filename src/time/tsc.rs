@@ -0,0 +1,60 @@
+//! The time stamp counter.
+
+use super::hpet::Hpet;
+use super::pit;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+/// Returns whether the TSC is invariant, ticking at a constant rate in every
+/// power state, according to CPUID.
+pub fn is_invariant() -> bool {
+    // `__cpuid` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// Read the TSC.
+pub fn read() -> u64 {
+    // `_rdtsc` is only unsafe on older toolchains.
+    #[allow(unused_unsafe)]
+    unsafe {
+        _rdtsc()
+    }
+}
+
+/// Measure the TSC frequency against the HPET if there is one, or the PIT.
+pub(super) fn calibrate(hpet: Option<&Hpet>) -> u64 {
+    // The longest interval the PIT can count, about 50ms.
+    const CALIBRATION_HZ: u32 = 20;
+
+    x86_64::instructions::interrupts::without_interrupts(|| match hpet {
+        Some(hpet) => {
+            // The HPET is timed over half the PIT countdown, which only
+            // runs out first if the HPET stops counting. The PIT countdown
+            // is measured instead then.
+            let hpet_ticks = hpet.frequency() / u64::from(2 * CALIBRATION_HZ);
+            pit::start_countdown(CALIBRATION_HZ);
+            let hpet_start = hpet.counter();
+            let tsc_start = read();
+            while hpet.counter().wrapping_sub(hpet_start) < hpet_ticks {
+                if pit::countdown_done() {
+                    return (read() - tsc_start) * u64::from(CALIBRATION_HZ);
+                }
+                core::hint::spin_loop();
+            }
+            let hpet_elapsed = hpet.counter().wrapping_sub(hpet_start);
+            let tsc_elapsed = read() - tsc_start;
+
+            (u128::from(tsc_elapsed) * u128::from(hpet.frequency()) / u128::from(hpet_elapsed))
+                as u64
+        }
+        None => {
+            pit::start_countdown(CALIBRATION_HZ);
+            let tsc_start = read();
+            pit::wait_countdown();
+
+            (read() - tsc_start) * u64::from(CALIBRATION_HZ)
+        }
+    })
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory::buddy::{BuddyFrameAllocator, MAX_ORDER};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+use x86_64::VirtAddr;
+
+/// The one allocator of this kernel, shared by the tests, which free every
+/// frame they allocate.
+static FRAMES: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::memory;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let frames = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAMES.lock() = Some(frames);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+/// Run `f` with the allocator created in `main`.
+fn with_frames<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    f(FRAMES.lock().as_mut().expect("frame allocator not created"))
+}
+
+/// The number of free blocks of every order.
+fn free_blocks(frames: &BuddyFrameAllocator) -> [usize; MAX_ORDER + 1] {
+    let mut blocks = [0; MAX_ORDER + 1];
+    for (order, count) in blocks.iter_mut().enumerate() {
+        *count = frames.free_blocks(order);
+    }
+    blocks
+}
+
+#[test_case]
+fn blocks_are_aligned() {
+    with_frames(|frames| {
+        for order in [0, 1, 4, 9] {
+            let block = frames.allocate(order).expect("out of memory");
+            assert!(block.is_aligned(4096u64 << order));
+            unsafe { frames.deallocate(block, order) };
+        }
+    });
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    with_frames(|frames| {
+        let free = frames.free_frames();
+
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().expect("no 2MiB block");
+        assert_eq!(frames.free_frames(), free - 512);
+
+        unsafe { frames.deallocate_frame(frame) };
+        assert_eq!(frames.free_frames(), free);
+    });
+}
+
+#[test_case]
+fn freed_blocks_coalesce() {
+    with_frames(|frames| {
+        let before = free_blocks(frames);
+
+        // Splitting a larger block for these leaves buddies behind, which
+        // must be merged back once both frames are freed.
+        let a = frames.allocate(0).expect("out of memory");
+        let b = frames.allocate(0).expect("out of memory");
+        assert_ne!(a, b);
+        unsafe {
+            frames.deallocate(a, 0);
+            frames.deallocate(b, 0);
+        }
+
+        assert_eq!(free_blocks(frames), before);
+    });
+}
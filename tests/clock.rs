@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use kernel_dev::rtc::SystemTime;
+use kernel_dev::time::{self, Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::memory::{self, BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    // The HPET registers are mapped with `memory::map_mmio`.
+    memory::install(mapper, frame_allocator);
+    time::init().expect("no clocksource");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+#[test_case]
+fn instants_are_monotonic() {
+    let mut previous = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= previous);
+        previous = now;
+    }
+}
+
+/// Measure a second of the RTC wall clock with the monotonic clock.
+#[test_case]
+fn clock_agrees_with_rtc() {
+    // Start on a second boundary, so a whole second is measured.
+    let second = SystemTime::now().date_time();
+    while SystemTime::now().date_time() == second {}
+
+    let start = Instant::now();
+    let second = SystemTime::now().date_time();
+    while SystemTime::now().date_time() == second {}
+    let elapsed = start.elapsed();
+
+    assert!(elapsed > Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1100), "{:?}", elapsed);
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory::{self, vmm};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::allocator;
+    use kernel_dev::memory::BuddyFrameAllocator;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+#[test_case]
+fn pages_are_mapped_on_first_access() {
+    let addr = vmm::vmalloc_lazy(4 * 4096).expect("vmalloc_lazy failed");
+    assert!(memory::walk(addr).is_none());
+    let faults = vmm::lazy_faults();
+
+    let ptr = (addr + 4096u64 + 8u64).as_mut_ptr::<u64>();
+    unsafe { ptr.write_volatile(0xdead_beef) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 0xdead_beef);
+
+    assert_eq!(vmm::lazy_faults(), faults + 1);
+    assert!(memory::walk(addr).is_none());
+    assert!(memory::walk(addr + 4096u64).is_some());
+
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+}
+
+#[test_case]
+fn lazy_pages_read_as_zero() {
+    let size = 3 * 4096;
+    let addr = vmm::vmalloc_lazy(size).expect("vmalloc_lazy failed");
+
+    let buffer = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), size) };
+    assert!(buffer.iter().all(|&byte| byte == 0));
+
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+}
+
+#[test_case]
+fn vfree_unmaps_touched_pages() {
+    let addr = vmm::vmalloc_lazy(2 * 4096).expect("vmalloc_lazy failed");
+    unsafe { addr.as_mut_ptr::<u8>().write_volatile(1) };
+    assert!(memory::walk(addr).is_some());
+
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+    assert!(memory::walk(addr).is_none());
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory::BitmapFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use x86_64::VirtAddr;
+
+/// The one allocator of this kernel, shared by the tests, which free every
+/// frame they allocate.
+static FRAMES: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::memory;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let frames = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAMES.lock() = Some(frames);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+/// Run `f` with the allocator created in `main`.
+fn with_frames<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    f(FRAMES.lock().as_mut().expect("frame allocator not created"))
+}
+
+#[test_case]
+fn counters_add_up() {
+    with_frames(|frames| {
+        assert!(frames.free_frames() > 0);
+        assert!(frames.reserved_frames() > 0);
+        assert_eq!(
+            frames.free_frames() + frames.reserved_frames() + frames.allocated_frames(),
+            frames.total_frames()
+        );
+    });
+}
+
+#[test_case]
+fn frames_are_distinct() {
+    with_frames(|frames| {
+        let a = frames.allocate_frame().expect("out of frames");
+        let b = frames.allocate_frame().expect("out of frames");
+        assert_ne!(a, b);
+
+        unsafe {
+            frames.deallocate_frame(a);
+            frames.deallocate_frame(b);
+        }
+    });
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    with_frames(|frames| {
+        let free = frames.free_frames();
+
+        let frame = frames.allocate_frame().expect("out of frames");
+        assert_eq!(frames.free_frames(), free - 1);
+        unsafe { frames.deallocate_frame(frame) };
+        assert_eq!(frames.free_frames(), free);
+
+        // The lowest free frame is always handed out first.
+        assert_eq!(frames.allocate_frame(), Some(frame));
+        unsafe { frames.deallocate_frame(frame) };
+    });
+}
@@ -15,16 +15,16 @@ entry_point!(main);
 
 fn main (boot_info: &'static BootInfo) -> ! {
     use kernel_dev::allocator;
-    use kernel_dev::memory::{self, BootInfoFrameAllocator};
+    use kernel_dev::memory::{self, BuddyFrameAllocator};
     use x86_64::VirtAddr;
 
     kernel_dev::init_kernel();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    let heap_size = allocator::boot_heap_size(&boot_info.memory_map);
+    allocator::init_heap_sized(&mut mapper, &mut frame_allocator, heap_size)
         .expect("heap initialization failed");
 
     test_main();
@@ -74,4 +74,29 @@ fn many_boxes_long_lived(){
     }
     assert_eq!(*long_lived, 1); // new
 
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn allocator_lock_is_counted() {
+    let before = kernel_dev::allocator::lock_stats().acquisitions;
+    let x = Box::new(7);
+    assert_eq!(*x, 7);
+    assert!(kernel_dev::allocator::lock_stats().acquisitions > before);
+}
+
+#[test_case]
+fn heap_is_sized_from_memory() {
+    use kernel_dev::allocator::{initial_heap_size, MAX_BOOT_HEAP_SIZE};
+
+    assert_eq!(initial_heap_size(0), HEAP_SIZE);
+    assert_eq!(initial_heap_size(128 << 20), 6_713_344);
+    assert_eq!(initial_heap_size(1 << 40), MAX_BOOT_HEAP_SIZE);
+    assert!(kernel_dev::allocator::heap_size() > HEAP_SIZE);
+}
+
+/// A vector far larger than `HEAP_SIZE` fits in a heap sized at boot.
+#[test_case]
+fn vec_larger_than_fixed_heap() {
+    let v = alloc::vec![1u8; 1024 * 1024];
+    assert_eq!(v.iter().map(|&b| b as usize).sum::<usize>(), 1024 * 1024);
+}
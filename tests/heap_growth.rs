@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::allocator::{self, HEAP_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::memory::{self, BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+#[test_case]
+fn large_vec_grows_heap() {
+    let size = 4 * HEAP_SIZE;
+    let v = vec![7u8; size];
+    assert!(allocator::heap_size() >= size);
+    assert!(v.iter().all(|&byte| byte == 7));
+}
+
+#[test_case]
+fn many_boxes_grow_heap() {
+    let boxes: Vec<Box<[u64; 64]>> = (0..HEAP_SIZE / 256)
+        .map(|i| Box::new([i as u64; 64]))
+        .collect();
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    for (i, boxed) in boxes.iter().enumerate() {
+        assert_eq!(boxed[63], i as u64);
+    }
+}
+
+#[test_case]
+fn heap_stops_at_limit() {
+    let size = allocator::heap_size();
+    allocator::set_heap_limit(size);
+
+    let mut v: Vec<u8> = Vec::new();
+    assert!(v.try_reserve(size + 1).is_err());
+    assert_eq!(allocator::heap_size(), size);
+
+    allocator::set_heap_limit(allocator::DEFAULT_HEAP_LIMIT);
+    assert!(v.try_reserve(size + 1).is_ok());
+}
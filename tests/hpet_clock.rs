@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use kernel_dev::rtc::SystemTime;
+use kernel_dev::time::{self, Clocksource, Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::memory::{self, BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+    // QEMU always provides an HPET.
+    time::init_with(Clocksource::Hpet).expect("no HPET");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+/// The specification asks for at least 10MHz.
+#[test_case]
+fn hpet_frequency_is_plausible() {
+    assert_eq!(time::clocksource(), Some(Clocksource::Hpet));
+
+    let frequency = time::frequency().expect("clock not started");
+    assert!(frequency >= 10_000_000, "{} Hz", frequency);
+    assert!(frequency <= 1_000_000_000, "{} Hz", frequency);
+}
+
+/// Measure a second of the RTC wall clock with the HPET.
+#[test_case]
+fn hpet_instants_advance() {
+    // Start on a second boundary, so a whole second is measured.
+    let second = SystemTime::now().date_time();
+    while SystemTime::now().date_time() == second {}
+
+    let start = Instant::now();
+    let second = SystemTime::now().date_time();
+    while SystemTime::now().date_time() == second {}
+    let elapsed = start.elapsed();
+
+    assert!(elapsed > Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1100), "{:?}", elapsed);
+}
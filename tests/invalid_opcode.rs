@@ -0,0 +1,39 @@
+//! Checks that an invalid opcode is routed into the crash report, which
+//! hands over to the panic handler.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use kernel_dev::crash::{self, INVALID_OPCODE};
+use kernel_dev::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode...\t");
+
+    kernel_dev::gdt::init_gdt();
+    kernel_dev::interrupts::init_idt();
+
+    unsafe { core::arch::asm!("ud2") };
+
+    serial_println!("[execution continued after ud2]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Any other panic means #UD did not reach its crash report.
+    if crash::is_report(info, INVALID_OPCODE) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    loop {}
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory::mmio::{self, MmioCache};
+use kernel_dev::memory::{self, CacheType};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The VGA text buffer is the one device every machine we run on has.
+const VGA_BUFFER: u64 = 0xb8000;
+const VGA_SIZE: usize = 80 * 25 * 2;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::allocator;
+    use kernel_dev::memory::BuddyFrameAllocator;
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+#[test_case]
+fn writes_reach_the_device() {
+    let region =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), VGA_SIZE) }.expect("map_mmio failed");
+    let offset = VGA_SIZE - 2;
+
+    region.write::<u16>(offset, 0x0f21);
+    assert_eq!(region.read::<u16>(offset), 0x0f21);
+
+    // The same cell, seen through the physical memory mapping.
+    let phys_mem_offset = memory::physical_memory_offset().unwrap();
+    let cell = (phys_mem_offset + VGA_BUFFER + offset as u64).as_ptr::<u16>();
+    assert_eq!(unsafe { cell.read_volatile() }, 0x0f21);
+}
+
+#[test_case]
+fn unaligned_start_is_kept() {
+    let phys = PhysAddr::new(VGA_BUFFER + 6);
+    let region = unsafe { memory::map_mmio(phys, 4) }.expect("map_mmio failed");
+
+    assert_eq!(region.base().as_u64() % 4096, 6);
+    let mapping = memory::walk(region.base()).expect("region is not mapped");
+    assert_eq!(mapping.phys_addr, phys);
+}
+
+#[test_case]
+fn cache_types_are_applied() {
+    let uncached =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), VGA_SIZE) }.expect("map_mmio failed");
+    let mapping = memory::walk(uncached.base()).expect("region is not mapped");
+    assert_eq!(mapping.flags.cache, CacheType::Uncacheable);
+    assert!(mapping.flags.writable);
+
+    let combining = unsafe {
+        mmio::map_mmio_with(
+            PhysAddr::new(VGA_BUFFER),
+            VGA_SIZE,
+            MmioCache::WriteCombining,
+        )
+    }
+    .expect("map_mmio failed");
+    let mapping = memory::walk(combining.base()).expect("region is not mapped");
+    assert_eq!(mapping.flags.cache, CacheType::WriteCombining);
+}
+
+#[test_case]
+fn drop_unmaps_region() {
+    let region =
+        unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), VGA_SIZE) }.expect("map_mmio failed");
+    let base = region.base();
+    assert!(memory::walk(base).is_some());
+
+    drop(region);
+    assert!(memory::walk(base).is_none());
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    memory::physical_memory_offset().expect("memory not initialised")
+}
+
+/// The physical memory mapping is where the bootloader uses huge pages.
+#[test_case]
+fn physical_memory_mapping() {
+    let vga = PhysAddr::new(0xb8123);
+    let addr = phys_mem_offset() + vga.as_u64();
+
+    let mapping = memory::walk(addr).expect("physical memory is not mapped");
+    assert_eq!(mapping.phys_addr, vga);
+    assert!(mapping.flags.writable);
+    assert!(mapping.level >= 2, "mapped by a 4KiB page");
+
+    let translated = unsafe { memory::translate_addr(addr, phys_mem_offset()) };
+    assert_eq!(translated, Some(vga));
+}
+
+#[test_case]
+fn code_is_executable() {
+    let addr = VirtAddr::new(phys_mem_offset as fn() -> VirtAddr as u64);
+    let mapping = memory::walk(addr).expect("code is not mapped");
+    assert!(!mapping.flags.no_execute);
+    assert!(!mapping.flags.user);
+}
+
+#[test_case]
+fn stack_is_writable() {
+    let value = 0u64;
+    let addr = VirtAddr::from_ptr(&value);
+
+    let mapping = memory::walk(addr).expect("stack is not mapped");
+    assert!(mapping.flags.writable);
+    // The bootloader maps the stack with 4KiB pages.
+    assert_eq!(mapping.level, 1);
+    assert_eq!(mapping.page_size(), 4096);
+
+    // The physical memory mapping leads back to the same value.
+    let alias = phys_mem_offset() + mapping.phys_addr.as_u64();
+    assert_eq!(unsafe { alias.as_ptr::<u64>().read_volatile() }, value);
+}
+
+#[test_case]
+fn regions_cover_physical_memory_mapping() {
+    let vga = phys_mem_offset() + 0xb8000u64;
+    let mut previous_end = 0;
+    let mut found = false;
+
+    memory::dump::for_each_region(|region| {
+        let start = region.start.as_u64();
+        assert!(start >= previous_end, "regions out of order");
+        previous_end = start.saturating_add(region.size);
+
+        if (start..previous_end).contains(&vga.as_u64()) {
+            assert_eq!(
+                region.phys_start + (vga.as_u64() - start),
+                PhysAddr::new(0xb8000)
+            );
+            found = true;
+        }
+    });
+    assert!(found, "VGA buffer missing from the physical memory mapping");
+}
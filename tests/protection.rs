@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_dev::memory::{self, protect, vmm};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+static RODATA: [u8; 16] = *b"read only, thank";
+static DATA: AtomicU64 = AtomicU64::new(1);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::allocator;
+    use kernel_dev::memory::BuddyFrameAllocator;
+
+    kernel_dev::init_kernel();
+    protect::enable_nx();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    let protection = protect::protect_kernel().expect("protect_kernel failed");
+    assert_eq!(protection.rwx_pages, 0);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+fn flags_of(addr: u64) -> memory::EffectiveFlags {
+    memory::walk(VirtAddr::new(addr))
+        .expect("address is not mapped")
+        .flags
+}
+
+#[test_case]
+fn write_protection_is_enabled() {
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+}
+
+#[test_case]
+fn no_segment_is_writable_and_executable() {
+    assert!(protect::kernel_segments().count() > 0);
+    assert!(protect::kernel_segments().all(|segment| !(segment.writable && segment.executable)));
+}
+
+#[test_case]
+fn code_is_read_only() {
+    let flags = flags_of(flags_of as fn(u64) -> memory::EffectiveFlags as u64);
+    assert!(!flags.writable);
+    assert!(!flags.no_execute);
+}
+
+#[test_case]
+fn rodata_is_not_executable() {
+    let flags = flags_of(RODATA.as_ptr() as u64);
+    assert!(!flags.writable);
+    assert!(flags.no_execute);
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    DATA.fetch_add(1, Ordering::Relaxed);
+    let flags = flags_of(&DATA as *const AtomicU64 as u64);
+    assert!(flags.writable);
+    assert!(flags.no_execute);
+}
+
+#[test_case]
+fn heap_and_stack_are_not_executable() {
+    let boxed = Box::new(7u64);
+    assert!(flags_of(&*boxed as *const u64 as u64).no_execute);
+
+    let local = 7u64;
+    assert!(flags_of(&local as *const u64 as u64).no_execute);
+}
+
+#[test_case]
+fn vmalloc_memory_is_not_executable() {
+    let addr = vmm::vmalloc(4096).expect("vmalloc failed");
+    assert!(flags_of(addr.as_u64()).no_execute);
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+}
+
+#[test_case]
+fn physical_memory_mapping_is_not_executable() {
+    let offset = memory::physical_memory_offset().unwrap();
+    assert!(flags_of(offset.as_u64() + 0x10_0000).no_execute);
+}
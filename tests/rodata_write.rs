@@ -0,0 +1,52 @@
+//! Checks that once the kernel is protected, a write to read-only data
+//! faults and is routed into the page fault crash report.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::crash::{self, PAGE_FAULT};
+use kernel_dev::memory::{self, protect, BuddyFrameAllocator};
+use kernel_dev::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+static RODATA: [u8; 16] = *b"read only, thank";
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("rodata_write::write_to_rodata...\t");
+
+    kernel_dev::init_kernel();
+    protect::enable_nx();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+    protect::protect_kernel().expect("protect_kernel failed");
+
+    let ptr = core::ptr::addr_of!(RODATA) as *mut u8;
+    unsafe { ptr.write_volatile(0) };
+
+    serial_println!("[execution continued after writing to rodata]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Any other panic means the write did not reach the page fault report.
+    if crash::is_report(info, PAGE_FAULT) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    loop {}
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use kernel_dev::rtc::alarm;
+use kernel_dev::rtc::{DateTime, SystemTime};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    kernel_dev::init_kernel();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+/// The date and time `seconds` from now.
+fn seconds_from_now(seconds: u64) -> DateTime {
+    DateTime::from_unix(SystemTime::now().date_time().to_unix() + seconds)
+}
+
+/// Halt until `done` returns true, or fail once `seconds` have passed.
+fn wait_for(seconds: u64, done: impl Fn() -> bool) {
+    let deadline = seconds_from_now(seconds);
+    while !done() {
+        assert!(
+            SystemTime::now().date_time() < deadline,
+            "alarm did not go off"
+        );
+        x86_64::instructions::hlt();
+    }
+}
+
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+fn set_fired() {
+    FIRED.store(true, Ordering::SeqCst);
+}
+
+#[test_case]
+fn alarm_calls_callback() {
+    FIRED.store(false, Ordering::SeqCst);
+    alarm::schedule(seconds_from_now(2), set_fired).expect("alarm not scheduled");
+
+    wait_for(5, || FIRED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn cancelled_alarm_does_not_fire() {
+    FIRED.store(false, Ordering::SeqCst);
+    let id = alarm::schedule(seconds_from_now(2), set_fired).expect("alarm not scheduled");
+    assert!(alarm::cancel(id));
+
+    let end = seconds_from_now(3);
+    while SystemTime::now().date_time() < end {
+        x86_64::instructions::hlt();
+    }
+    assert!(!FIRED.load(Ordering::SeqCst));
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+fn flag_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+#[test_case]
+fn alarm_wakes_task() {
+    WOKEN.store(false, Ordering::SeqCst);
+    let waker = flag_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut future = alarm::wait_until(seconds_from_now(2)).expect("alarm not scheduled");
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Pending);
+
+    wait_for(5, || WOKEN.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Ready(()));
+}
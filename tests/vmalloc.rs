@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel_dev::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel_dev::memory::vmm::{self, AreaKind, VmError};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel_dev::allocator;
+    use kernel_dev::memory::{self, BuddyFrameAllocator};
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    kernel_dev::memory::with_kernel_memory(|memory| memory.frames.free_frames())
+        .expect("kernel memory not installed")
+}
+
+#[test_case]
+fn vmalloc_memory_is_usable() {
+    let size = 5 * 4096 + 100;
+    let addr = vmm::vmalloc(size).expect("vmalloc failed");
+    assert_eq!(vmm::kind_of(addr), Some(AreaKind::Vmalloc));
+
+    let buffer = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), size) };
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert!(buffer.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+}
+
+#[test_case]
+fn vfree_returns_frames() {
+    let free = free_frames();
+
+    let addr = vmm::vmalloc(16 * 4096).expect("vmalloc failed");
+    assert!(free_frames() <= free - 16);
+    unsafe { vmm::vfree(addr).expect("vfree failed") };
+
+    // Page tables created for the mapping stay behind.
+    assert!(free_frames() >= free - 3);
+}
+
+#[test_case]
+fn areas_do_not_overlap() {
+    let a = vmm::vmalloc(4096).expect("vmalloc failed");
+    let b = vmm::vmalloc(4096).expect("vmalloc failed");
+
+    // Each area is followed by a guard page.
+    assert!(a + 2 * 4096u64 <= b || b + 2 * 4096u64 <= a);
+
+    unsafe {
+        vmm::vfree(a).expect("vfree failed");
+        vmm::vfree(b).expect("vfree failed");
+    }
+}
+
+#[test_case]
+fn released_range_is_reused() {
+    let a = vmm::vmalloc(4096).expect("vmalloc failed");
+    unsafe { vmm::vfree(a).expect("vfree failed") };
+
+    assert_eq!(vmm::vmalloc(4096), Ok(a));
+    unsafe { vmm::vfree(a).expect("vfree failed") };
+}
+
+#[test_case]
+fn unknown_addresses_are_rejected() {
+    assert_eq!(vmm::vmalloc(0), Err(VmError::InvalidSize));
+    assert_eq!(
+        unsafe { vmm::vfree(VirtAddr::new(0x7777_0000_1000)) },
+        Err(VmError::NotReserved)
+    );
+    assert_eq!(vmm::reserve(AreaKind::Heap, 4096), Err(VmError::OutOfSpace));
+}
+
+#[test_case]
+fn huge_sizes_are_rejected() {
+    assert_eq!(vmm::vmalloc(usize::MAX), Err(VmError::OutOfSpace));
+    assert_eq!(
+        vmm::vmalloc_lazy(usize::MAX - 4096),
+        Err(VmError::OutOfSpace)
+    );
+    assert_eq!(
+        vmm::reserve(AreaKind::Stack, u64::MAX - 4096),
+        Err(VmError::OutOfSpace)
+    );
+}
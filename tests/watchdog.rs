@@ -0,0 +1,52 @@
+//! Checks that the watchdog NMI catches code spinning with interrupts
+//! disabled, which stops the timer tick.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use kernel_dev::debug::watchdog::{self, Lockup, Mode};
+use kernel_dev::memory::{self, BuddyFrameAllocator};
+use kernel_dev::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("watchdog::interrupts_disabled_lockup...\t");
+
+    kernel_dev::init_kernel();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+
+    assert!(
+        kernel_dev::interrupts::init_apic(),
+        "no APIC to deliver the NMI"
+    );
+    watchdog::set_handler(lockup_detected).expect("handler already set");
+    assert_eq!(watchdog::start(Duration::from_secs(1)), Ok(Mode::Nmi));
+
+    x86_64::instructions::interrupts::disable();
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+fn lockup_detected(lockup: Lockup) -> ! {
+    assert_eq!(lockup, Lockup::TimerTick);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel_dev::test_panic_handler(info)
+}